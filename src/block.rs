@@ -1,6 +1,7 @@
 extern crate bincode;
 
 use std::fmt;
+use std::sync::atomic::AtomicBool;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use bincode::Error;
@@ -10,42 +11,98 @@ use rustc_serialize::hex::ToHex;
 
 use crate::merkel_tree::MerkelTree;
 use crate::mining_error::MiningError;
-use crate::proof_of_work::{convert_u64_to_u8_array, ProofOfWork, TARGET_BITS};
+use crate::proof_of_work::{convert_u64_to_u8_array, MiningStats, ProofOfWork};
 use crate::transaction::Transaction;
 
 const HASH_BYTE_SIZE: usize = 32;
 
 pub type Sha256Hash = [u8; HASH_BYTE_SIZE];
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Block {
     pub timestamp: u64,
     pub transactions: Vec<Transaction>,
     pub hash: Sha256Hash,
     pub prev_block_hash: Sha256Hash,
+    /// Number of blocks before this one, the genesis block has height 0
+    pub height: u64,
+    pub merkle_root: Sha256Hash,
+    /// Merkle root over the witness ids, which the transaction ids leave out, so
+    /// the header commits to the signatures too.
+    pub witness_root: Sha256Hash,
+    /// Big-endian target the block hash must not exceed
+    pub target: Sha256Hash,
     pub nonce: u64,
 }
 
+pub fn current_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: Sha256Hash) -> Result<Self, MiningError> {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-
+    pub fn new(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Sha256Hash,
+        height: u64,
+        target: Sha256Hash,
+        timestamp: u64,
+        threads: usize,
+    ) -> Result<Self, MiningError> {
+        let cancel = AtomicBool::new(false);
+        Self::mine(transactions, prev_block_hash, height, target, timestamp, threads, &cancel)
+            .map(|(block, _stats)| block)
+    }
+    /// Mines a block on `threads` workers until a solution is found or `cancel` is
+    /// set, e.g. by a node that received a competing block. Whenever the nonce space
+    /// runs out the extra-nonce of the coinbase is bumped, or the timestamp if there
+    /// is no coinbase, and the search starts over with a fresh header.
+    pub fn mine(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Sha256Hash,
+        height: u64,
+        target: Sha256Hash,
+        timestamp: u64,
+        threads: usize,
+        cancel: &AtomicBool,
+    ) -> Result<(Self, MiningStats), MiningError> {
         let mut block = Self {
             timestamp,
             transactions,
             prev_block_hash,
+            height,
+            merkle_root: Sha256Hash::default(),
+            witness_root: Sha256Hash::default(),
+            target,
             hash: Sha256Hash::default(),
             nonce: 0,
         };
+        block.merkle_root = block.hash_transactions();
+        block.witness_root = block.hash_witnesses();
 
-        let pow = ProofOfWork::new(&block);
-        let (nonce, hash) = pow.run()?;
-        block.hash = hash;
-        block.nonce = nonce;
-        Ok(block)
+        let mut stats = MiningStats::default();
+        loop {
+            match ProofOfWork::new(&block).run(threads, cancel, &mut stats) {
+                Ok((nonce, hash)) => {
+                    block.hash = hash;
+                    block.nonce = nonce;
+                    return Ok((block, stats));
+                }
+                Err(MiningError::Iteration) => block.roll_extra_nonce(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    fn roll_extra_nonce(&mut self) {
+        match self.transactions.first_mut() {
+            Some(coinbase) if coinbase.is_coinbase() => {
+                coinbase.increment_extra_nonce();
+                self.merkle_root = self.hash_transactions();
+                self.witness_root = self.hash_witnesses();
+            }
+            _ => self.timestamp += 1,
+        }
     }
-    fn hash_transactions(&self) -> Sha256Hash {
+    pub(crate) fn hash_transactions(&self) -> Sha256Hash {
         let mut tx_hashes = Vec::new();
         for tx in &self.transactions {
             tx_hashes.push(tx.id.to_vec());
@@ -53,16 +110,33 @@ impl Block {
         let tree = MerkelTree::new(tx_hashes);
         *tree.root_node.hash()
     }
+    pub(crate) fn hash_witnesses(&self) -> Sha256Hash {
+        let mut wtx_hashes = Vec::new();
+        for tx in &self.transactions {
+            wtx_hashes.push(tx.wtxid().to_vec());
+        }
+        let tree = MerkelTree::new(wtx_hashes);
+        *tree.root_node.hash()
+    }
     pub(crate) fn headers(&self) -> Vec<u8> {
         let mut vec = Vec::new();
         vec.extend(&convert_u64_to_u8_array(self.timestamp));
-        vec.extend(&convert_u64_to_u8_array(TARGET_BITS));
+        vec.extend(&self.target);
         vec.extend(&self.prev_block_hash);
-        vec.extend(&self.hash_transactions());
+        vec.extend(&convert_u64_to_u8_array(self.height));
+        vec.extend(&self.merkle_root);
+        vec.extend(&self.witness_root);
         vec
     }
-    pub fn genesis_block(coinbase: Transaction) -> Result<Self, MiningError> {
-        Self::new(vec![Transaction::from(coinbase)], Sha256Hash::default())
+    pub fn genesis_block(coinbase: Transaction, target: Sha256Hash) -> Result<Self, MiningError> {
+        Self::new(
+            vec![coinbase],
+            Sha256Hash::default(),
+            0,
+            target,
+            current_timestamp(),
+            1,
+        )
     }
     pub fn serialize(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
@@ -78,11 +152,19 @@ impl fmt::Display for Block {
         write!(f,
                "Hash: {:?} \n \
                 Prev Hash: {:?} \n \
+                Height: {} \n \
                 Timestamp: {} \n \
+                Target: {:?} \n \
                 nonce: {}",
                self.hash.to_hex(),
                self.prev_block_hash.to_hex(),
+               self.height,
                self.timestamp,
-               self.nonce)
+               self.target.to_hex(),
+               self.nonce)?;
+        for tx in &self.transactions {
+            write!(f, " \n {}", tx)?;
+        }
+        Ok(())
     }
 }
\ No newline at end of file
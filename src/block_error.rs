@@ -0,0 +1,120 @@
+use std::{error, fmt};
+
+/// Consensus rule a block broke. Transaction level variants carry the index
+/// of the offending transaction inside the block.
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    UnknownParent,
+    KnownInvalid,
+    InvalidParent,
+    PrevHashMismatch,
+    BadHeight,
+    MerkleRootMismatch,
+    WitnessRootMismatch,
+    BadTarget,
+    InvalidProofOfWork,
+    TimeTooNew,
+    TimeTooOld,
+    NoCoinbase,
+    CoinbaseTooLarge,
+    MultipleCoinbase(usize),
+    IdMismatch(usize),
+    DuplicateTransaction(usize),
+    NonFinalTransaction(usize),
+    DoubleSpend(usize),
+    MissingInput(usize),
+    ImmatureCoinbase(usize),
+    SequenceLocked(usize),
+    InvalidTransaction(usize),
+    OutputsExceedInputs(usize),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockError::UnknownParent => write!(f, "parent block is unknown"),
+            BlockError::KnownInvalid => write!(f, "block already failed validation"),
+            BlockError::InvalidParent => write!(f, "parent block failed validation"),
+            BlockError::PrevHashMismatch => write!(f, "block does not extend the current tip"),
+            BlockError::BadHeight => write!(f, "block height does not follow its parent"),
+            BlockError::MerkleRootMismatch => {
+                write!(f, "merkle root does not match the transactions")
+            }
+            BlockError::WitnessRootMismatch => write!(f, "witness root does not match the signatures"),
+            BlockError::BadTarget => write!(f, "target does not match the difficulty rule"),
+            BlockError::InvalidProofOfWork => write!(f, "block hash does not satisfy its target"),
+            BlockError::TimeTooNew => write!(f, "block timestamp is too far in the future"),
+            BlockError::TimeTooOld => {
+                write!(f, "block timestamp is not after the median of recent blocks")
+            }
+            BlockError::NoCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockError::CoinbaseTooLarge => {
+                write!(f, "coinbase claims more than the subsidy plus fees")
+            }
+            BlockError::MultipleCoinbase(idx) => {
+                write!(f, "transaction {} is a second coinbase", idx)
+            }
+            BlockError::IdMismatch(idx) => {
+                write!(f, "transaction {} id does not match its content", idx)
+            }
+            BlockError::DuplicateTransaction(idx) => {
+                write!(f, "transaction {} repeats an id that has unspent outputs", idx)
+            }
+            BlockError::NonFinalTransaction(idx) => {
+                write!(f, "transaction {} is locked until a later height or time", idx)
+            }
+            BlockError::DoubleSpend(idx) => {
+                write!(f, "transaction {} spends an output already spent in the block", idx)
+            }
+            BlockError::MissingInput(idx) => {
+                write!(f, "transaction {} spends an unknown or spent output", idx)
+            }
+            BlockError::ImmatureCoinbase(idx) => {
+                write!(f, "transaction {} spends a coinbase output before it matured", idx)
+            }
+            BlockError::SequenceLocked(idx) => {
+                write!(f, "transaction {} spends an output younger than its input sequence", idx)
+            }
+            BlockError::InvalidTransaction(idx) => {
+                write!(f, "transaction {} failed verification", idx)
+            }
+            BlockError::OutputsExceedInputs(idx) => {
+                write!(f, "transaction {} spends more than its inputs", idx)
+            }
+        }
+    }
+}
+
+impl error::Error for BlockError {
+    fn description(&self) -> &str {
+        match *self {
+            BlockError::UnknownParent => "parent block is unknown",
+            BlockError::KnownInvalid => "block already failed validation",
+            BlockError::InvalidParent => "parent block failed validation",
+            BlockError::PrevHashMismatch => "block does not extend the current tip",
+            BlockError::BadHeight => "block height does not follow its parent",
+            BlockError::MerkleRootMismatch => "merkle root does not match the transactions",
+            BlockError::WitnessRootMismatch => "witness root does not match the signatures",
+            BlockError::BadTarget => "target does not match the difficulty rule",
+            BlockError::InvalidProofOfWork => "block hash does not satisfy its target",
+            BlockError::TimeTooNew => "block timestamp is too far in the future",
+            BlockError::TimeTooOld => "block timestamp is not after the median of recent blocks",
+            BlockError::NoCoinbase => "first transaction is not a coinbase",
+            BlockError::CoinbaseTooLarge => "coinbase claims more than the subsidy plus fees",
+            BlockError::MultipleCoinbase(_) => "block has more than one coinbase",
+            BlockError::IdMismatch(_) => "transaction id does not match its content",
+            BlockError::DuplicateTransaction(_) => "transaction id already has unspent outputs",
+            BlockError::NonFinalTransaction(_) => "transaction is locked until a later block",
+            BlockError::DoubleSpend(_) => "output spent twice inside the block",
+            BlockError::MissingInput(_) => "input spends an unknown or spent output",
+            BlockError::ImmatureCoinbase(_) => "coinbase output spent before it matured",
+            BlockError::SequenceLocked(_) => "output spent before the input sequence allows",
+            BlockError::InvalidTransaction(_) => "transaction failed verification",
+            BlockError::OutputsExceedInputs(_) => "transaction spends more than its inputs",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
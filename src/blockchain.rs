@@ -1,20 +1,61 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{channel, Receiver, Sender};
 
-use rkv::Value;
+use num_bigint::BigUint;
+use rkv::{SingleStore, StoreOptions, Value, Writer};
 
-use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::hex::ToHex;
 use secp256k1::{SecretKey};
 
-use crate::block::{Block, Sha256Hash};
+use crate::block::{current_timestamp, Block, Sha256Hash};
+use crate::block_error::BlockError;
+use crate::chain_error::ChainError;
+use crate::chain_params::ChainParams;
+use crate::coin_selection::{CoinSelector, Selection};
 use crate::mining_error::MiningError;
-use crate::script_lang::ScriptSig;
+use crate::proof_of_work::{
+    block_work, retarget, target_from_bits, target_to_bytes, MiningStats, ProofOfWork,
+};
 use crate::store::Store;
-use crate::transaction::{Transaction, TransactionError, TXInput, TXOutput};
-use crate::wallet::{address_to_pub_hash, hash_pub_key, KeyHash, PubKeyBytes, Wallet, Wallets};
+use crate::transaction::{Fee, OutPoint, Transaction, TransactionError, TXOutput};
+use crate::tx_builder::TransactionBuilder;
+use crate::tx_index::TxIndex;
+use crate::utxo::{OutsSet, UTXOSet};
+use crate::wallet::{hash_pub_key, Address, InvalidAddress, KeyHash, Wallet, Wallets};
+
+const HEIGHTBUCKET: &str = "heights";
+const WORKBUCKET: &str = "work";
+/// Stored side branch blocks that failed validation
+const INVALIDBUCKET: &str = "invalid";
+/// Encoding of the stored blocks and indexes. Stores without a version were
+/// written before lock times, sequences and the witness root and can't be read.
+pub const STORE_VERSION: u32 = 1;
+const VERSIONKEY: &str = "version";
+
+/// Change of the main chain, sent to subscribers in the order it happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainEvent {
+    BlockConnected { hash: Sha256Hash, height: u64 },
+    BlockDisconnected { hash: Sha256Hash, height: u64 },
+}
+
+pub struct Balance {
+    /// Value that can be spent in the next block
+    pub confirmed: u64,
+    /// Coinbase rewards still waiting to mature
+    pub immature: u64,
+    pub outs: Vec<OutPoint>,
+}
 
 pub struct Blockchain {
     tip: Option<Sha256Hash>,
+    /// Height of the tip block, the genesis block has height 0
+    height: u64,
+    params: ChainParams,
+    mining_threads: usize,
+    subscribers: Vec<Sender<ChainEvent>>,
     path: String,
     pub(crate) store: Store,
 }
@@ -23,259 +64,714 @@ impl Blockchain {
     pub fn new(
         path_str: String,
         wallets: &mut Wallets,
-    ) -> Result<(Blockchain, Option<String>), MiningError> {
+    ) -> Result<(Blockchain, Option<String>), ChainError> {
+        Self::with_params(path_str, wallets, ChainParams::default())
+    }
+
+    pub fn with_params(
+        path_str: String,
+        wallets: &mut Wallets,
+        params: ChainParams,
+    ) -> Result<(Blockchain, Option<String>), ChainError> {
+        params.validate()?;
         let store = Store::new(&path_str, "block".to_owned());
-        let env = store.rkv();
-        let single_store = store.single_store();
-        let reader = env.read().unwrap();
-        let mut address: Option<String> = None;
-        let gen_block;
-        let tip = match single_store.get(&reader, "l") {
-            Ok(l_opt) => match l_opt {
-                Some(l) => {
-                    if let Value::Blob(val) = l {
-                        let mut a: [u8; 32] = Default::default();
-                        a.copy_from_slice(val);
-                        a
-                    } else {
-                        panic!("Wrong format")
+        let tip = {
+            let env = store.rkv();
+            let single_store = store.single_store();
+            let reader = env.read().unwrap();
+            match single_store.get(&reader, "l") {
+                Ok(l_opt) => match l_opt {
+                    Some(l) => {
+                        if let Value::Blob(val) = l {
+                            let mut a: [u8; 32] = Default::default();
+                            a.copy_from_slice(val);
+                            Some(a)
+                        } else {
+                            panic!("Wrong format")
+                        }
                     }
+                    None => None,
+                },
+                Err(e) => {
+                    panic!("{}", e);
                 }
-                None => {
-                    let wallet = wallets.create_wallet();
-                    let wallet_address = wallet.get_address();
-                    let coinbase_transaction =
-                        Transaction::new_coinbase_tx(&wallet_address, "genesis block".to_string());
-                    gen_block = Block::genesis_block(coinbase_transaction)?;
-                    address = Some(wallet_address);
-                    let mut writer = env.write().unwrap();
-                    single_store
-                        .put(
-                            &mut writer,
-                            &gen_block.hash,
-                            &Value::Blob(&gen_block.serialize()),
-                        )
-                        .unwrap();
-                    single_store
-                        .put(&mut writer, "l", &Value::Blob(&gen_block.hash))
-                        .unwrap();
-                    writer.commit().unwrap();
-                    gen_block.hash
-                }
-            },
-            Err(e) => {
-                panic!("{}", e);
             }
         };
-        Ok((
-            Blockchain {
-                store: Store::clone(&store),
-                path: path_str,
-                tip: Some(tip),
-            },
-            address,
-        ))
+        let mut blockchain = Blockchain {
+            store,
+            path: path_str,
+            tip,
+            height: 0,
+            params,
+            mining_threads: 1,
+            subscribers: vec![],
+        };
+        let mut address: Option<String> = None;
+        match blockchain.tip {
+            Some(tip) => {
+                let version = blockchain.store_version();
+                if version != Some(STORE_VERSION) {
+                    return Err(ChainError::StoreVersion(version));
+                }
+                blockchain.height = blockchain.get_block_by_hash(&tip).unwrap().height;
+                // Stores created before the indexes were maintained have empty buckets
+                let utxo_set = UTXOSet::new(&blockchain);
+                if utxo_set.is_empty() {
+                    utxo_set.reindex();
+                }
+                let tx_index = TxIndex::new(&blockchain);
+                if tx_index.is_empty() {
+                    tx_index.reindex();
+                }
+                if blockchain.stored_work(&tip).is_none() {
+                    blockchain.reindex_work();
+                }
+            }
+            None => {
+                blockchain.write_store_version();
+                let wallet = wallets.create_wallet();
+                let wallet_address = wallet.get_address();
+                let coinbase_transaction = Transaction::new_coinbase_tx(
+                    &wallet_address,
+                    "genesis block".to_string(),
+                    blockchain.params.subsidy(0),
+                )
+                .expect("new wallet address");
+                let target = target_to_bytes(&target_from_bits(blockchain.params.pow_limit_bits));
+                let gen_block = Block::genesis_block(coinbase_transaction, target)?;
+                let work = block_work(&BigUint::from_bytes_be(&gen_block.target));
+                blockchain.connect_block(&gen_block, Some(&work));
+                address = Some(wallet_address);
+            }
+        }
+        Ok((blockchain, address))
     }
 
+    fn store_version(&self) -> Option<u32> {
+        let rkv = self.store.rkv();
+        let single_store = self.store.single_store();
+        let reader = rkv.read().unwrap();
+        match single_store.get(&reader, VERSIONKEY).unwrap() {
+            Some(Value::Blob(val)) if val.len() == 4 => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(val);
+                Some(u32::from_be_bytes(bytes))
+            }
+            Some(_) => panic!("Wrong format"),
+            None => None,
+        }
+    }
+    fn write_store_version(&self) {
+        let rkv = self.store.rkv();
+        let single_store = self.store.single_store();
+        let mut writer = rkv.write().unwrap();
+        single_store
+            .put(&mut writer, VERSIONKEY, &Value::Blob(&STORE_VERSION.to_be_bytes()))
+            .unwrap();
+        writer.commit().unwrap();
+    }
     pub fn verify_transaction(&self, tx: &Transaction) -> bool {
         if tx.is_coinbase() {
             return true;
         }
         let mut prev_txs: HashMap<String, Transaction> = Default::default();
         for vin in tx.vin.iter() {
-            let borrow_vin = vin.borrow();
-            prev_txs.insert(
-                borrow_vin.tx_id.to_hex(),
-                self.find_transaction(&borrow_vin.tx_id),
-            );
+            match self.find_transaction(&vin.tx_id) {
+                Some(prev_tx) => prev_txs.insert(vin.tx_id.to_hex(), prev_tx),
+                None => return false,
+            };
         }
         tx.verify(&prev_txs)
     }
-    pub fn find_transaction(&self, tx_id: &Sha256Hash) -> Transaction {
-        for block in self.iter() {
-            for tx in block.transactions {
-                if tx.id == *tx_id {
-                    return tx.clone();
-                }
+    /// Looks up a confirmed transaction through the transaction index.
+    pub fn find_transaction(&self, tx_id: &Sha256Hash) -> Option<Transaction> {
+        self.locate_transaction(tx_id).map(|(tx, _height)| tx)
+    }
+    /// Confirmed transaction together with the height of the block containing it.
+    pub(crate) fn locate_transaction(&self, tx_id: &Sha256Hash) -> Option<(Transaction, u64)> {
+        let location = TxIndex::new(self).get(tx_id)?;
+        let mut block = self.get_block_by_hash(&location.block_hash)?;
+        if (location.position as usize) < block.transactions.len() {
+            Some((block.transactions.swap_remove(location.position as usize), block.height))
+        } else {
+            None
+        }
+    }
+    /// Returns a channel receiving every block connected to or disconnected from
+    /// the main chain from now on.
+    pub fn subscribe(&mut self) -> Receiver<ChainEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+    fn notify(&mut self, event: ChainEvent) {
+        self.subscribers.retain(|sender| sender.send(event).is_ok());
+    }
+    /// Height of the tip, the genesis block has height 0.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+    /// Number of worker threads `mine_block` searches nonces on.
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.mining_threads = threads.max(1);
+    }
+    pub fn mine_block(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Result<MiningStats, MiningError> {
+        self.mine_block_cancellable(transactions, &AtomicBool::new(false))
+    }
+    /// Like `mine_block`, but gives up with `MiningError::Cancelled` once `cancel` is set.
+    pub fn mine_block_cancellable(
+        &mut self,
+        transactions: Vec<Transaction>,
+        cancel: &AtomicBool,
+    ) -> Result<MiningStats, MiningError> {
+        // Fail before spending any work on a block that would be rejected
+        self.check_transactions(&transactions, self.height + 1)
+            .map_err(MiningError::InvalidBlock)?;
+
+        match self.tip {
+            Some(hash) => {
+                let target = target_to_bytes(&self.expected_target(&hash, self.height + 1));
+                let timestamp = current_timestamp().max(self.median_time_past(&hash) + 1);
+                let height = self.height + 1;
+                let threads = self.mining_threads;
+                let (block, stats) =
+                    Block::mine(transactions, hash, height, target, timestamp, threads, cancel)?;
+                self.add_block(block).map_err(MiningError::InvalidBlock)?;
+                Ok(stats)
             }
+            None => Err(MiningError::NoParent),
         }
-        panic!("No transacton for id {}", tx_id.to_hex())
     }
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<(), MiningError> {
-        let block: Block;
+    /// Accepts a mined or received block. A block extending the tip is fully
+    /// validated and connected. A block on a side branch only has its header
+    /// checked and is stored with its cumulative work; once a branch has more
+    /// work than the main chain the chain reorganizes onto it. Blocks that failed
+    /// validation, and their children, are refused, but a different body with the
+    /// same hash may replace a failed one.
+    pub fn add_block(&mut self, block: Block) -> Result<(), BlockError> {
+        if let Some(stored) = self.get_block_by_hash(&block.hash) {
+            if !self.is_invalid(&block.hash) {
+                return Ok(());
+            }
+            if stored.serialize() == block.serialize() {
+                return Err(BlockError::KnownInvalid);
+            }
+        }
+        let parent = self
+            .get_block_by_hash(&block.prev_block_hash)
+            .ok_or(BlockError::UnknownParent)?;
+        if self.is_invalid(&parent.hash) {
+            return Err(BlockError::InvalidParent);
+        }
+        let work =
+            self.chain_work(&parent.hash) + block_work(&BigUint::from_bytes_be(&block.target));
 
-        let rkv = self.store.rkv();
-        let single_store = self.store.single_store();
+        if self.tip == Some(parent.hash) {
+            self.validate_block(&block)?;
+            self.connect_block(&block, Some(&work));
+            return Ok(());
+        }
 
-        for tx in transactions.iter() {
-            if !self.verify_transaction(tx) {
-                panic!("Failed transaction")
+        self.check_header(&block, parent.height + 1)?;
+        self.store_block(&block, &work);
+        if work > self.chain_work(&self.tip.unwrap()) {
+            self.reorganize(&block)?;
+        }
+        Ok(())
+    }
+    /// Switches the main chain to the branch ending at `new_tip`. Blocks above the
+    /// fork point are disconnected, then the branch is validated and connected
+    /// block by block; if one of its blocks is invalid the old chain is restored
+    /// and that block and the rest of the branch are marked invalid.
+    fn reorganize(&mut self, new_tip: &Block) -> Result<(), BlockError> {
+        let mut branch: Vec<Block> = vec![];
+        let mut fork_height = 0;
+        for block in self.iter_from(new_tip.hash) {
+            if self.is_main_chain(&block) {
+                fork_height = block.height;
+                break;
             }
+            branch.push(block);
         }
+        branch.reverse();
 
-        match self.tip {
-            Some(hash) => {
-                block = Block::new(transactions, hash)?;
-                let mut writer = rkv.write().unwrap();
-                single_store
-                    .put(&mut writer, &block.hash, &Value::Blob(&block.serialize()))
-                    .unwrap();
-                single_store
-                    .put(&mut writer, "l", &Value::Blob(&block.hash))
-                    .unwrap();
-                writer.commit().unwrap();
-            }
-            None => {
-                return Err(MiningError::NoParent);
+        let mut disconnected: Vec<Block> = vec![];
+        while self.height > fork_height {
+            disconnected.push(self.disconnect_tip());
+        }
+        for (connected, block) in branch.iter().enumerate() {
+            if let Err(e) = self.validate_block(block) {
+                for _ in 0..connected {
+                    self.disconnect_tip();
+                }
+                for block in disconnected.iter().rev() {
+                    self.connect_block(block, None);
+                }
+                self.mark_invalid(&branch[connected..]);
+                return Err(e);
             }
+            self.connect_block(block, None);
         }
-        self.tip = Some(block.hash);
         Ok(())
     }
-    pub fn find_unspent_transactions(&self, pub_key: &PubKeyBytes) -> Vec<Transaction> {
-        let mut unspent_txs: Vec<Transaction> = vec![];
-        let pub_key_hash = hash_pub_key(pub_key);
-        let mut spent_txs: HashMap<String, Vec<i64>> = HashMap::new();
-        for block in self.iter() {
-            for tx in block.transactions {
-                let tx_id = tx.id.to_hex();
-                'outs: for (out_idx, out) in tx.vout.iter().enumerate() {
-                    match spent_txs.get(&tx_id) {
-                        Some(spent_outs) => {
-                            for val in spent_outs {
-                                if *val == out_idx as i64 {
-                                    continue 'outs;
-                                }
+    /// Drops the work of stored blocks that failed validation, so no branch through
+    /// them is preferred again, and records them to refuse their children.
+    fn mark_invalid(&self, blocks: &[Block]) {
+        let rkv = self.store.rkv();
+        let work_store = rkv.open_single(WORKBUCKET, StoreOptions::create()).unwrap();
+        let invalid_store = rkv.open_single(INVALIDBUCKET, StoreOptions::create()).unwrap();
+        let mut writer = rkv.write().unwrap();
+        for block in blocks {
+            invalid_store
+                .put(&mut writer, block.hash, &Value::Blob(&[]))
+                .unwrap();
+            if work_store.get(&writer, block.hash).unwrap().is_some() {
+                work_store.delete(&mut writer, block.hash).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+    }
+    fn is_invalid(&self, hash: &Sha256Hash) -> bool {
+        let rkv = self.store.rkv();
+        let invalid_store = rkv.open_single(INVALIDBUCKET, StoreOptions::create()).unwrap();
+        let reader = rkv.read().unwrap();
+        invalid_store.get(&reader, hash).unwrap().is_some()
+    }
+    fn is_main_chain(&self, block: &Block) -> bool {
+        block.height <= self.height
+            && self
+                .get_block_by_height(block.height)
+                .is_some_and(|main| main.hash == block.hash)
+    }
+    /// Total work of the chain ending at the stored block `hash`.
+    fn chain_work(&self, hash: &Sha256Hash) -> BigUint {
+        self.stored_work(hash).unwrap()
+    }
+    fn stored_work(&self, hash: &Sha256Hash) -> Option<BigUint> {
+        let rkv = self.store.rkv();
+        let work_store = rkv.open_single(WORKBUCKET, StoreOptions::create()).unwrap();
+        let reader = rkv.read().unwrap();
+        match work_store.get(&reader, hash).unwrap() {
+            Some(Value::Blob(val)) => Some(BigUint::from_bytes_be(val)),
+            Some(_) => panic!("Wrong format"),
+            None => None,
+        }
+    }
+    /// Recomputes the cumulative work of every main chain block, for stores
+    /// created before it was recorded.
+    fn reindex_work(&self) {
+        let mut work = BigUint::default();
+        let mut works: Vec<(Sha256Hash, BigUint)> = vec![];
+        for block in self.range(0..self.height + 1) {
+            work += block_work(&BigUint::from_bytes_be(&block.target));
+            works.push((block.hash, work.clone()));
+        }
+        let rkv = self.store.rkv();
+        let work_store = rkv.open_single(WORKBUCKET, StoreOptions::create()).unwrap();
+        let mut writer = rkv.write().unwrap();
+        for (hash, work) in works {
+            work_store
+                .put(&mut writer, hash, &Value::Blob(&work.to_bytes_be()))
+                .unwrap();
+        }
+        writer.commit().unwrap();
+    }
+    /// Checks every consensus rule for a block that extends the current tip:
+    /// header linkage, target and proof of work, merkle and witness roots, timestamps,
+    /// coinbase placement and that every input spends an unspent output with a
+    /// valid signature.
+    pub fn validate_block(&self, block: &Block) -> Result<(), BlockError> {
+        let height = match self.tip {
+            Some(tip) if tip == block.prev_block_hash => self.height + 1,
+            None if block.prev_block_hash == Sha256Hash::default() => 0,
+            _ => return Err(BlockError::PrevHashMismatch),
+        };
+        self.check_header(block, height)?;
+        self.check_transactions(&block.transactions, height)
+    }
+    /// Transaction rules of a block at `height` extending the tip: a single leading
+    /// coinbase claiming at most the subsidy plus the fees, ids that don't repeat
+    /// one with unspent outputs, which would overwrite them, lock times and input
+    /// sequences that have passed, and inputs spending
+    /// outputs that are unspent on the chain or created earlier in the same block,
+    /// with valid signatures and at least the value of the outputs.
+    fn check_transactions(
+        &self,
+        transactions: &[Transaction],
+        height: u64,
+    ) -> Result<(), BlockError> {
+        let utxo_set = UTXOSet::new(self);
+        let lock_time_cutoff = self.lock_time_cutoff();
+        let mut fees: u64 = 0;
+        let mut spent: HashSet<(Sha256Hash, i64)> = HashSet::new();
+        let mut block_txs: HashMap<String, Transaction> = HashMap::new();
+        for (idx, tx) in transactions.iter().enumerate() {
+            if idx == 0 && !tx.is_coinbase() {
+                return Err(BlockError::NoCoinbase);
+            }
+            if idx > 0 && tx.is_coinbase() {
+                return Err(BlockError::MultipleCoinbase(idx));
+            }
+            if !tx.has_valid_id() {
+                return Err(BlockError::IdMismatch(idx));
+            }
+            if !tx.is_final(height, lock_time_cutoff) {
+                return Err(BlockError::NonFinalTransaction(idx));
+            }
+            if !tx.is_coinbase() {
+                let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+                for vin in &tx.vin {
+                    if !spent.insert((vin.tx_id, vin.vout)) {
+                        return Err(BlockError::DoubleSpend(idx));
+                    }
+                    let (prev_tx, prev_height) = match block_txs.get(&vin.tx_id.to_hex()) {
+                        Some(prev_tx) => (prev_tx.clone(), height),
+                        None => {
+                            if utxo_set.get_output(&vin.tx_id, vin.vout).is_none() {
+                                return Err(BlockError::MissingInput(idx));
+                            }
+                            match self.locate_transaction(&vin.tx_id) {
+                                Some(found) => found,
+                                None => return Err(BlockError::MissingInput(idx)),
                             }
                         }
-                        None => (),
+                    };
+                    if prev_tx.is_coinbase() && !self.coinbase_matures(prev_height, height) {
+                        return Err(BlockError::ImmatureCoinbase(idx));
                     }
-
-                    if out.is_locker_with_key(&pub_key_hash) {
-                        unspent_txs.push(tx.clone()) // Ask about it, can i save it better?
+                    if height < prev_height.saturating_add(vin.sequence as u64) {
+                        return Err(BlockError::SequenceLocked(idx));
                     }
-                    if !tx.is_coinbase() {
-                        for vin in &tx.vin {
-                            let ref_vin = vin.borrow();
-                            if ref_vin.uses_key(pub_key) {
-                                let in_tx_id = ref_vin.tx_id.to_hex();
-
-                                match spent_txs.get_mut(&in_tx_id) {
-                                    Some(vec) => vec.push(ref_vin.vout),
-                                    None => {
-                                        spent_txs.insert(in_tx_id, vec![ref_vin.vout]);
-                                    }
-                                }
-                            }
+                    if vin.vout < 0 || vin.vout as usize >= prev_tx.vout.len() {
+                        return Err(BlockError::MissingInput(idx));
+                    }
+                    prev_txs.insert(vin.tx_id.to_hex(), prev_tx);
+                }
+                if !tx.verify(&prev_txs) {
+                    return Err(BlockError::InvalidTransaction(idx));
+                }
+                fees = tx
+                    .fee(&prev_txs)
+                    .and_then(|fee| fees.checked_add(fee))
+                    .ok_or(BlockError::OutputsExceedInputs(idx))?;
+            }
+            if block_txs.contains_key(&tx.id.to_hex()) || utxo_set.contains(&tx.id) {
+                return Err(BlockError::DuplicateTransaction(idx));
+            }
+            block_txs.insert(tx.id.to_hex(), tx.clone());
+        }
+        let coinbase = transactions.first().ok_or(BlockError::NoCoinbase)?;
+        match coinbase.output_value() {
+            Some(reward) if reward <= self.coinbase_limit(height, fees) => Ok(()),
+            _ => Err(BlockError::CoinbaseTooLarge),
+        }
+    }
+    /// Rules that only depend on the block's ancestors, so they can also be
+    /// checked for blocks on a side branch.
+    fn check_header(&self, block: &Block, height: u64) -> Result<(), BlockError> {
+        if block.height != height {
+            return Err(BlockError::BadHeight);
+        }
+
+        if block.merkle_root != block.hash_transactions() {
+            return Err(BlockError::MerkleRootMismatch);
+        }
+        if block.witness_root != block.hash_witnesses() {
+            return Err(BlockError::WitnessRootMismatch);
+        }
+        let expected_target = self.expected_target(&block.prev_block_hash, height);
+        if BigUint::from_bytes_be(&block.target) != expected_target {
+            return Err(BlockError::BadTarget);
+        }
+        if !ProofOfWork::new(block).validate(&expected_target) {
+            return Err(BlockError::InvalidProofOfWork);
+        }
+
+        if block.timestamp > current_timestamp() + self.params.max_future_block_time {
+            return Err(BlockError::TimeTooNew);
+        }
+        if height > 0 && block.timestamp <= self.median_time_past(&block.prev_block_hash) {
+            return Err(BlockError::TimeTooOld);
+        }
+        Ok(())
+    }
+    /// Whether a coinbase confirmed at `created` may be spent by a block at `height`.
+    pub(crate) fn coinbase_matures(&self, created: u64, height: u64) -> bool {
+        height >= created.saturating_add(self.params.coinbase_maturity)
+    }
+    /// Whether the outputs of a UTXO record may be spent in the next block.
+    pub(crate) fn is_spendable(&self, outs_set: &OutsSet) -> bool {
+        !outs_set.coinbase || self.coinbase_matures(outs_set.height, self.height + 1)
+    }
+    fn coinbase_limit(&self, height: u64, fees: u64) -> u64 {
+        self.params.subsidy(height).saturating_add(fees)
+    }
+    /// Most the coinbase of the next block may claim when its other transactions
+    /// pay `fees`.
+    pub fn coinbase_value(&self, fees: u64) -> u64 {
+        self.coinbase_limit(self.height + 1, fees)
+    }
+    /// Coins in existence: the value of every unspent output of the main chain.
+    pub fn total_supply(&self) -> u64 {
+        UTXOSet::new(self).total_value()
+    }
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+    /// Time lock times of transactions in the next block are compared to, the
+    /// median time past of the tip rather than the block's own timestamp.
+    fn lock_time_cutoff(&self) -> u64 {
+        self.tip.map_or(0, |tip| self.median_time_past(&tip))
+    }
+    /// Whether the lock time of `tx` allows it into the next block.
+    pub fn is_final(&self, tx: &Transaction) -> bool {
+        tx.is_final(self.height + 1, self.lock_time_cutoff())
+    }
+    /// Median timestamp of the last `median_time_span` blocks ending at `hash`.
+    pub(crate) fn median_time_past(&self, hash: &Sha256Hash) -> u64 {
+        let mut timestamps: Vec<u64> = self
+            .iter_from(*hash)
+            .take(self.params.median_time_span)
+            .map(|block| block.timestamp)
+            .collect();
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
+    /// Target a block at `height` built on top of `prev_block_hash` has to commit to.
+    /// It only changes every `retarget_interval` blocks, based on how long the
+    /// previous window took.
+    pub(crate) fn expected_target(&self, prev_block_hash: &Sha256Hash, height: u64) -> BigUint {
+        if height == 0 {
+            return target_from_bits(self.params.pow_limit_bits);
+        }
+        let prev_block = self.iter_from(*prev_block_hash).next().unwrap();
+        let prev_target = BigUint::from_bytes_be(&prev_block.target);
+        if !height.is_multiple_of(self.params.retarget_interval) {
+            return prev_target;
+        }
+        let first_block = self
+            .iter_from(*prev_block_hash)
+            .nth(self.params.retarget_interval as usize - 1)
+            .unwrap();
+        let actual_timespan = prev_block.timestamp.saturating_sub(first_block.timestamp);
+        retarget(&prev_target, actual_timespan, &self.params)
+    }
+    /// Stores the block and the cumulative work of the chain ending at it, without
+    /// touching the main chain.
+    fn store_block(&self, block: &Block, work: &BigUint) {
+        let rkv = self.store.rkv();
+        let single_store = self.store.single_store();
+        let work_store = rkv.open_single(WORKBUCKET, StoreOptions::create()).unwrap();
+        let invalid_store = rkv.open_single(INVALIDBUCKET, StoreOptions::create()).unwrap();
+
+        let mut writer = rkv.write().unwrap();
+        write_block(&mut writer, single_store, work_store, invalid_store, block, work);
+        writer.commit().unwrap();
+    }
+    /// Makes a block the new tip and applies it to the indexes in one write
+    /// transaction. A block that is not stored yet comes with its cumulative `work`
+    /// and is stored in the same transaction, so it is never left stored but
+    /// not applied.
+    fn connect_block(&mut self, block: &Block, work: Option<&BigUint>) {
+        {
+            let rkv = self.store.rkv();
+            let single_store = self.store.single_store();
+            let heights_store = rkv.open_single(HEIGHTBUCKET, StoreOptions::create()).unwrap();
+            let work_store = rkv.open_single(WORKBUCKET, StoreOptions::create()).unwrap();
+            let invalid_store = rkv.open_single(INVALIDBUCKET, StoreOptions::create()).unwrap();
+            let utxo_set = UTXOSet::new(self);
+            let tx_index = TxIndex::new(self);
+
+            let mut writer = rkv.write().unwrap();
+            if let Some(work) = work {
+                write_block(&mut writer, single_store, work_store, invalid_store, block, work);
+            }
+            single_store
+                .put(&mut writer, "l", &Value::Blob(&block.hash))
+                .unwrap();
+            heights_store
+                .put(&mut writer, block.height.to_be_bytes(), &Value::Blob(&block.hash))
+                .unwrap();
+            utxo_set.update(&mut writer, block);
+            tx_index.update(&mut writer, block);
+            writer.commit().unwrap();
+        }
+        self.tip = Some(block.hash);
+        self.height = block.height;
+        self.notify(ChainEvent::BlockConnected {
+            hash: block.hash,
+            height: block.height,
+        });
+    }
+    /// Removes the tip from the main chain, restoring the outputs it spent, and
+    /// returns it. The block itself stays stored.
+    fn disconnect_tip(&mut self) -> Block {
+        let block = self.get_block_by_hash(&self.tip.unwrap()).unwrap();
+        // Read the spent outputs before the write transaction is opened
+        let mut restored: Vec<(Sha256Hash, i64, TXOutput, OutsSet)> = vec![];
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in &tx.vin {
+                let (prev_tx, height) = self.locate_transaction(&vin.tx_id).unwrap();
+                let out = prev_tx.vout[vin.vout as usize].clone();
+                let record = OutsSet {
+                    height,
+                    coinbase: prev_tx.is_coinbase(),
+                    outs: vec![],
+                };
+                restored.push((vin.tx_id, vin.vout, out, record));
+            }
+        }
+        {
+            let rkv = self.store.rkv();
+            let single_store = self.store.single_store();
+            let heights_store = rkv.open_single(HEIGHTBUCKET, StoreOptions::create()).unwrap();
+            let utxo_set = UTXOSet::new(self);
+            let tx_index = TxIndex::new(self);
+
+            let mut writer = rkv.write().unwrap();
+            single_store
+                .put(&mut writer, "l", &Value::Blob(&block.prev_block_hash))
+                .unwrap();
+            heights_store
+                .delete(&mut writer, block.height.to_be_bytes())
+                .unwrap();
+            utxo_set.revert(&mut writer, &block, restored);
+            tx_index.remove(&mut writer, &block);
+            writer.commit().unwrap();
+        }
+        self.tip = Some(block.prev_block_hash);
+        self.height = block.height - 1;
+        self.notify(ChainEvent::BlockDisconnected {
+            hash: block.hash,
+            height: block.height,
+        });
+        block
+    }
+    /// Scans the whole chain and collects every output that has not been spent yet.
+    pub(crate) fn find_utxo(&self) -> HashMap<Sha256Hash, OutsSet> {
+        let mut utxo: HashMap<Sha256Hash, OutsSet> = HashMap::new();
+        let mut spent_txos: HashMap<Sha256Hash, Vec<i64>> = HashMap::new();
+        for block in self.iter() {
+            for tx in block.transactions.iter().rev() {
+                for (out_idx, out) in tx.vout.iter().enumerate() {
+                    let out_idx = out_idx as i64;
+                    if let Some(spent_outs) = spent_txos.get(&tx.id) {
+                        if spent_outs.contains(&out_idx) {
+                            continue;
                         }
                     }
-                    if block.prev_block_hash.len() == 0 {
-                        break;
+                    let outs_set = utxo.entry(tx.id).or_default();
+                    outs_set.height = block.height;
+                    outs_set.coinbase = tx.is_coinbase();
+                    outs_set.outs.push((out_idx, out.clone()));
+                }
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent_txos.entry(vin.tx_id).or_default().push(vin.vout);
                     }
                 }
             }
         }
-        unspent_txs
-    }
-    pub fn find_outs(self, _pub_key_hash: &KeyHash) -> Vec<TXOutput> {
-        let outs: Vec<TXOutput> = vec![];
-//        TODO implement balance
-//        let unspent_txs = self.find_unspent_transactions(pub_key_hash);
-//        for tx in unspent_txs {
-//            for out in tx.vout {
-//                if out.is_locker_with_key(pub_key_hash) {
-//                    outs.push(out)
-//                }
-//            }
-//        }
-        outs
-    }
-    pub fn get_balance(self, address: &String) -> u64 {
-        let pub_key_hash = &address_to_pub_hash(address);
-        let mut balance = 0;
-        let outs = self.find_outs(&pub_key_hash);
-        for out in outs {
-            balance += out.value;
-        }
-        balance
+        utxo
+    }
+    pub fn reindex_utxo(&self) {
+        UTXOSet::new(self).reindex();
+    }
+    pub fn reindex_transactions(&self) {
+        TxIndex::new(self).reindex();
+    }
+    pub fn find_outs(&self, pub_key_hash: &KeyHash) -> Vec<OutPoint> {
+        UTXOSet::new(self).find_utxo(pub_key_hash)
     }
+    pub fn get_balance(&self, address: &str) -> Result<Balance, InvalidAddress> {
+        let hash = match address.parse()? {
+            Address::PubKeyHash(hash) | Address::ScriptHash(hash) => hash,
+        };
+        let outs = self.find_outs(&hash);
+        let (mature, immature): (Vec<&OutPoint>, Vec<&OutPoint>) =
+            outs.iter().partition(|out| out.mature);
+        Ok(Balance {
+            confirmed: mature.iter().map(|out| out.value).sum(),
+            immature: immature.iter().map(|out| out.value).sum(),
+            outs,
+        })
+    }
+    /// Pays `amount` to `to` plus `fee`, returning the change to the sender.
+    /// `TransactionBuilder` supports several recipients and a change address.
     pub fn new_utxo_transaction(
         &self,
         from: &Wallet,
         to: String,
         amount: u64,
+        fee: Fee,
     ) -> Result<Transaction, TransactionError> {
-        let mut inputs: Vec<RefCell<TXInput>> = vec![];
-        let mut outputs: Vec<TXOutput> = vec![];
-        let (acc, valid_outs) = self.find_spendable_outs(from.clone(), amount);
-        if acc < amount {
-            return Err(TransactionError::NotEnoughMoney);
-        }
-
-        for (tx_id_hex, outs) in valid_outs.iter() {
-            let mut tx_id = Sha256Hash::default();
-
-            tx_id.copy_from_slice(&tx_id_hex.from_hex().unwrap()[..]);
-
-            for out in outs {
-                let input = TXInput {
-                    tx_id,
-                    vout: *out,
-                    script_sig: ScriptSig {
-                        pub_key: from.public_key.clone(),
-                        signature: [0; 64],
-                    },
-                };
-                inputs.push(RefCell::new(input));
-            }
-        }
-        outputs.push(TXOutput::new(amount, &to));
-
-        if acc > amount {
-            outputs.push(TXOutput::new(amount, &from.get_address()));
-        }
-        let tx = Transaction::new(inputs, outputs);
-        Ok(self.sign_transaction(&tx, &from.private_key()))
+        TransactionBuilder::new(self, from).pay(&to, amount).fee(fee).build()
     }
-    fn sign_transaction(&self, tx: &Transaction, priv_key: &SecretKey) -> Transaction {
+    pub(crate) fn sign_transaction(
+        &self,
+        tx: &Transaction,
+        priv_key: &SecretKey,
+    ) -> Result<Transaction, TransactionError> {
         let mut prev_txs: HashMap<String, Transaction> = Default::default();
         for vin in tx.vin.iter() {
-            let borrow_vin = vin.borrow();
-            prev_txs.insert(
-                borrow_vin.tx_id.to_hex(),
-                self.find_transaction(&borrow_vin.tx_id),
-            );
+            let prev_tx = self
+                .find_transaction(&vin.tx_id)
+                .ok_or(TransactionError::UnknownInput)?;
+            prev_txs.insert(vin.tx_id.to_hex(), prev_tx);
         }
-        tx.sign(priv_key, &prev_txs).unwrap()
+        Ok(tx.sign(priv_key, &prev_txs).unwrap())
     }
-    fn find_spendable_outs(&self, from: &Wallet, amount: u64) -> (u64, HashMap<String, Vec<i64>>) {
-        let unspent_txs = self.find_unspent_transactions(&from.public_key);
+    /// Lets `selector` pick spendable outputs of the wallet worth at least `amount`.
+    pub(crate) fn find_spendable_outs(
+        &self,
+        from: &Wallet,
+        amount: u64,
+        selector: &dyn CoinSelector,
+    ) -> Option<Selection> {
         let pub_key_hash = hash_pub_key(&from.public_key);
-        let mut unspent_outs: HashMap<String, Vec<i64>> = HashMap::new();
-        let mut acc = 0;
-
-        'work: for tx in unspent_txs {
-            let tx_id = tx.id.to_hex();
-            for (id, out) in tx.vout.iter().enumerate() {
-                if out.is_locker_with_key(&pub_key_hash) {
-                    acc += out.value;
-                    match unspent_outs.get_mut(&tx_id) {
-                        Some(vec) => vec.push(id as i64),
-                        None => {
-                            unspent_outs.insert(tx_id.clone(), vec![id as i64]);
-                        }
-                    }
-                    if acc >= amount {
-                        break 'work;
-                    }
+        let available = UTXOSet::new(self).find_spendable_outputs(&pub_key_hash);
+        selector.select(&available, amount)
+    }
+    pub fn iter(&self) -> BlockchainIterator {
+        self.into_iter()
+    }
+    pub fn get_block_by_hash(&self, hash: &Sha256Hash) -> Option<Block> {
+        read_block(&self.store, hash)
+    }
+    /// Block of the main chain at `height`, looked up through the height index.
+    pub fn get_block_by_height(&self, height: u64) -> Option<Block> {
+        let hash = {
+            let rkv = self.store.rkv();
+            let heights_store = rkv.open_single(HEIGHTBUCKET, StoreOptions::create()).unwrap();
+            let reader = rkv.read().unwrap();
+            match heights_store.get(&reader, height.to_be_bytes()).unwrap() {
+                Some(Value::Blob(val)) => {
+                    let mut hash = Sha256Hash::default();
+                    hash.copy_from_slice(val);
+                    hash
                 }
+                Some(_) => panic!("Wrong format"),
+                None => return None,
             }
+        };
+        self.get_block_by_hash(&hash)
+    }
+    /// Walks the main chain forward over the given heights.
+    pub fn range(&self, heights: Range<u64>) -> BlockRangeIterator<'_> {
+        BlockRangeIterator {
+            blockchain: self,
+            heights,
         }
-
-        (acc, unspent_outs)
     }
-    pub fn iter(&self) -> BlockchainIterator {
-        self.into_iter()
+    /// Walks backward starting at the block with the given hash.
+    pub(crate) fn iter_from(&self, hash: Sha256Hash) -> BlockchainIterator {
+        BlockchainIterator {
+            store: Store::clone(&self.store),
+            current_hash: Some(hash),
+        }
     }
 }
 
@@ -310,24 +806,503 @@ impl Iterator for BlockchainIterator {
     type Item = Block;
 
     fn next(&mut self) -> Option<Block> {
-        let rkv = self.store.rkv();
-        let reader = rkv.read().unwrap();
-        let single_store = self.store.single_store();
         if let Some(ref hash) = self.current_hash {
-            match single_store.get(&reader, hash).unwrap() {
-                None => None,
-                Some(l) => {
-                    if let Value::Blob(val) = l {
-                        let block = Block::from_bytes(&val.to_vec()).unwrap();
-                        self.current_hash = Some(block.prev_block_hash);
-                        Some(block)
-                    } else {
-                        panic!("Wrong format")
-                    }
-                }
-            }
+            let block = read_block(&self.store, hash)?;
+            self.current_hash = Some(block.prev_block_hash);
+            Some(block)
         } else {
             None
         }
     }
 }
+
+pub struct BlockRangeIterator<'a> {
+    blockchain: &'a Blockchain,
+    heights: Range<u64>,
+}
+
+impl<'a> Iterator for BlockRangeIterator<'a> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let height = self.heights.next()?;
+        self.blockchain.get_block_by_height(height)
+    }
+}
+
+/// Puts the block and its cumulative work into the block, work and invalid buckets
+/// inside the caller's write transaction. The buckets have to be opened before it.
+fn write_block(
+    writer: &mut Writer,
+    single_store: SingleStore,
+    work_store: SingleStore,
+    invalid_store: SingleStore,
+    block: &Block,
+    work: &BigUint,
+) {
+    single_store
+        .put(writer, block.hash, &Value::Blob(&block.serialize()))
+        .unwrap();
+    work_store
+        .put(writer, block.hash, &Value::Blob(&work.to_bytes_be()))
+        .unwrap();
+    // A body replacing one that failed validation has not failed yet
+    if invalid_store.get(writer, block.hash).unwrap().is_some() {
+        invalid_store.delete(writer, block.hash).unwrap();
+    }
+}
+
+fn read_block(store: &Store, hash: &Sha256Hash) -> Option<Block> {
+    let rkv = store.rkv();
+    let reader = rkv.read().unwrap();
+    let single_store = store.single_store();
+    match single_store.get(&reader, hash).unwrap() {
+        None => None,
+        Some(l) => {
+            if let Value::Blob(val) = l {
+                Some(Block::from_bytes(&val.to_vec()).unwrap())
+            } else {
+                panic!("Wrong format")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use crate::block::{current_timestamp, Block};
+    use crate::block_error::BlockError;
+    use crate::chain_error::ChainError;
+    use crate::chain_params::ChainParams;
+    use crate::mempool::Mempool;
+    use crate::mempool_error::MempoolError;
+    use crate::proof_of_work::{ProofOfWork, target_from_bits};
+    use crate::script_lang::StackValues;
+    use crate::test_utils::{block_on, spend, test_chain, test_chain_with, unsigned_spend};
+    use crate::transaction::{Fee, Transaction, TXInput, TXOutput};
+    use crate::tx_builder::TransactionBuilder;
+    use crate::wallet::Wallets;
+
+    use super::{Blockchain, ChainEvent, Value, STORE_VERSION, VERSIONKEY};
+
+    #[test]
+    fn get_balance() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+
+        let balance = bc.get_balance(&address).unwrap();
+        assert_eq!(balance.confirmed, 5000);
+        assert_eq!(balance.outs.len(), 1);
+        assert_eq!(balance.outs[0].height, 0);
+
+        let miner = wallets.create_wallet().get_address();
+        let tx = Transaction::new_coinbase_tx(&miner, "".to_string(), 5000).unwrap();
+        bc.mine_block(vec![tx.clone()]).unwrap();
+
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 5000);
+        let balance = bc.get_balance(&miner).unwrap();
+        assert_eq!(balance.confirmed, 5000);
+        let out = &balance.outs[0];
+        assert_eq!((out.tx_id, out.vout, out.value, out.height), (tx.id, 0, 5000, 1));
+    }
+
+    #[test]
+    fn retarget_after_window() {
+        let (_dir, mut wallets, mut bc, _address) = test_chain();
+        let pow_limit = target_from_bits(ChainParams::test().pow_limit_bits);
+        let miner = wallets.create_wallet().get_address();
+
+        for data in 1..=ChainParams::test().retarget_interval {
+            let tx = Transaction::new_coinbase_tx(&miner, data.to_string(), 5000).unwrap();
+            bc.mine_block(vec![tx]).unwrap();
+        }
+        let blocks: Vec<_> = bc.iter().collect();
+        let tip = &blocks[0];
+        assert!(BigUint::from_bytes_be(&tip.target) < pow_limit);
+        for block in &blocks[1..] {
+            assert_eq!(BigUint::from_bytes_be(&block.target), pow_limit);
+        }
+        let expected = bc.expected_target(&tip.prev_block_hash, bc.height);
+        assert!(ProofOfWork::new(tip).validate(&expected));
+        assert!(!ProofOfWork::new(tip).validate(&pow_limit));
+    }
+
+    fn next_block(bc: &Blockchain, transactions: Vec<Transaction>) -> Block {
+        block_on(bc, &bc.get_block_by_hash(&bc.tip.unwrap()).unwrap(), transactions)
+    }
+
+    #[test]
+    fn validate_block() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let coinbase = || Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::default()).unwrap();
+        let block = next_block(&bc, vec![coinbase(), tx.clone()]);
+        assert_eq!(bc.validate_block(&block), Ok(()));
+
+        let mut tampered = next_block(&bc, vec![coinbase(), tx.clone()]);
+        tampered.transactions.pop();
+        assert_eq!(bc.validate_block(&tampered), Err(BlockError::MerkleRootMismatch));
+
+        // Swapping a signature keeps the transaction id but not the witness id
+        let mut tampered = block.clone();
+        match tampered.transactions[1].vin[0].script_sig.values[0] {
+            StackValues::Signature(ref mut signature) => signature[0] ^= 1,
+            _ => panic!("input is not signed"),
+        }
+        assert_eq!(tampered.merkle_root, tampered.hash_transactions());
+        assert_eq!(bc.validate_block(&tampered), Err(BlockError::WitnessRootMismatch));
+
+        let mut tampered = next_block(&bc, vec![coinbase()]);
+        tampered.nonce += 1;
+        assert_eq!(bc.validate_block(&tampered), Err(BlockError::InvalidProofOfWork));
+
+        let orphan =
+            Block::new(vec![coinbase()], [1; 32], 1, block.target, block.timestamp, 1).unwrap();
+        assert_eq!(bc.validate_block(&orphan), Err(BlockError::PrevHashMismatch));
+
+        let no_coinbase = next_block(&bc, vec![tx.clone()]);
+        assert_eq!(bc.validate_block(&no_coinbase), Err(BlockError::NoCoinbase));
+
+        let double_spend = next_block(&bc, vec![coinbase(), tx.clone(), tx.clone()]);
+        assert_eq!(bc.validate_block(&double_spend), Err(BlockError::DoubleSpend(2)));
+
+        let mut forged = tx.clone();
+        forged.vout[0].value = 2000;
+        let forged = next_block(&bc, vec![coinbase(), forged]);
+        assert_eq!(bc.validate_block(&forged), Err(BlockError::IdMismatch(1)));
+
+        let reused = block.transactions[0].clone();
+        bc.add_block(block).unwrap();
+        let replay = next_block(&bc, vec![coinbase(), tx]);
+        assert_eq!(bc.validate_block(&replay), Err(BlockError::MissingInput(1)));
+        // A second copy would overwrite the unspent outputs of the first
+        let duplicate = next_block(&bc, vec![reused]);
+        assert_eq!(bc.validate_block(&duplicate), Err(BlockError::DuplicateTransaction(0)));
+    }
+
+    #[test]
+    fn blocks_by_height() {
+        let (_dir, _wallets, mut bc, address) = test_chain();
+        for _ in 0..3 {
+            let tx = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+            bc.mine_block(vec![tx]).unwrap();
+        }
+
+        let tip = bc.iter().next().unwrap();
+        assert_eq!(tip.height, 3);
+        assert_eq!(bc.get_block_by_height(3).unwrap().hash, tip.hash);
+        assert_eq!(bc.get_block_by_hash(&tip.hash).unwrap().height, 3);
+        assert!(bc.get_block_by_height(4).is_none());
+
+        let heights: Vec<u64> = bc.range(1..3).map(|block| block.height).collect();
+        assert_eq!(heights, vec![1, 2]);
+        let forward: Vec<_> = bc.range(0..4).map(|block| block.hash).collect();
+        let mut backward: Vec<_> = bc.iter().map(|block| block.hash).collect();
+        backward.reverse();
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn find_transaction() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let tx = bc.new_utxo_transaction(from, to, 1000, Fee::default()).unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+        bc.mine_block(vec![coinbase, tx.clone()]).unwrap();
+
+        assert_eq!(bc.find_transaction(&tx.id).unwrap().id, tx.id);
+        assert!(bc.find_transaction(&[7; 32]).is_none());
+
+        bc.reindex_transactions();
+        assert_eq!(bc.find_transaction(&tx.id).unwrap().id, tx.id);
+        assert!(bc.verify_transaction(&tx));
+    }
+
+    #[test]
+    fn store_version() {
+        let (dir, mut wallets, bc, _) = test_chain();
+        let path = dir.path().join("block").to_str().unwrap().to_string();
+        let reopen = |wallets: &mut Wallets| {
+            Blockchain::with_params(path.clone(), wallets, ChainParams::test()).map(|_| ())
+        };
+        assert_eq!(bc.store_version(), Some(STORE_VERSION));
+        assert!(reopen(&mut wallets).is_ok());
+
+        let rkv = bc.store.rkv();
+        let single_store = bc.store.single_store();
+        let mut writer = rkv.write().unwrap();
+        let newer = (STORE_VERSION + 1).to_be_bytes();
+        single_store.put(&mut writer, VERSIONKEY, &Value::Blob(&newer)).unwrap();
+        writer.commit().unwrap();
+        let newer = reopen(&mut wallets);
+        assert!(matches!(newer, Err(ChainError::StoreVersion(Some(v))) if v == STORE_VERSION + 1));
+
+        // Stores written before the version key can't be decoded and are refused
+        let mut writer = rkv.write().unwrap();
+        single_store.delete(&mut writer, VERSIONKEY).unwrap();
+        writer.commit().unwrap();
+        assert!(matches!(reopen(&mut wallets), Err(ChainError::StoreVersion(None))));
+    }
+
+    #[test]
+    fn reorganize_to_most_work() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let miner = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let coinbase =
+            |address: &String| Transaction::new_coinbase_tx(address, "".to_string(), 5000).unwrap();
+        let genesis = bc.get_block_by_height(0).unwrap();
+
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::default()).unwrap();
+        let main1 = next_block(&bc, vec![coinbase(&address), tx.clone()]);
+        bc.add_block(main1.clone()).unwrap();
+        let events = bc.subscribe();
+
+        let side1 = block_on(&bc, &genesis, vec![coinbase(&miner)]);
+        bc.add_block(side1.clone()).unwrap();
+        assert_eq!(bc.tip, Some(main1.hash));
+        assert_eq!(bc.add_block(side1.clone()), Ok(()));
+        let side2 = block_on(&bc, &side1, vec![coinbase(&miner)]);
+        bc.add_block(side2.clone()).unwrap();
+
+        assert_eq!(bc.tip, Some(side2.hash));
+        assert_eq!(bc.height, 2);
+        assert_eq!(bc.get_block_by_height(1).unwrap().hash, side1.hash);
+        let received: Vec<ChainEvent> = events.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                ChainEvent::BlockDisconnected { hash: main1.hash, height: 1 },
+                ChainEvent::BlockConnected { hash: side1.hash, height: 1 },
+                ChainEvent::BlockConnected { hash: side2.hash, height: 2 },
+            ]
+        );
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 5000);
+        assert_eq!(bc.get_balance(&to).unwrap().confirmed, 0);
+        assert_eq!(bc.get_balance(&miner).unwrap().confirmed, 10000);
+        assert!(bc.find_transaction(&tx.id).is_none());
+
+        // A heavier branch replaying the spend of main1 fails and the chain is restored
+        let main2 = block_on(&bc, &main1, vec![coinbase(&address), tx]);
+        bc.add_block(main2.clone()).unwrap();
+        let main3 = block_on(&bc, &main2, vec![coinbase(&address)]);
+        assert_eq!(bc.add_block(main3.clone()), Err(BlockError::MissingInput(1)));
+        // The failed branch is refused instead of being validated again
+        assert_eq!(bc.add_block(main3.clone()), Err(BlockError::KnownInvalid));
+        let main4 = block_on(&bc, &main3, vec![coinbase(&address)]);
+        assert_eq!(bc.add_block(main4), Err(BlockError::InvalidParent));
+        assert_eq!(bc.tip, Some(side2.hash));
+        assert_eq!(bc.get_block_by_height(1).unwrap().hash, side1.hash);
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 5000);
+        assert_eq!(bc.get_balance(&miner).unwrap().confirmed, 10000);
+    }
+
+    #[test]
+    fn reorganize_spent_in_block() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let miner = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let coinbase =
+            |address: &String| Transaction::new_coinbase_tx(address, "".to_string(), 5000).unwrap();
+        let genesis = bc.get_block_by_height(0).unwrap();
+
+        // The child spends every output of its parent inside the same block
+        let parent = spend(&genesis.transactions[0], from, &address, 5000);
+        let child = spend(&parent, from, &to, 5000);
+        let main1 = next_block(&bc, vec![coinbase(&address), parent.clone(), child.clone()]);
+        bc.add_block(main1).unwrap();
+        assert_eq!(bc.get_balance(&to).unwrap().confirmed, 5000);
+
+        let side1 = block_on(&bc, &genesis, vec![coinbase(&miner)]);
+        bc.add_block(side1.clone()).unwrap();
+        let side2 = block_on(&bc, &side1, vec![coinbase(&miner)]);
+        bc.add_block(side2.clone()).unwrap();
+
+        assert_eq!(bc.tip, Some(side2.hash));
+        assert!(bc.find_transaction(&parent.id).is_none());
+        assert!(bc.find_transaction(&child.id).is_none());
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 5000);
+        assert_eq!(bc.get_balance(&to).unwrap().confirmed, 0);
+        assert_eq!(bc.get_balance(&miner).unwrap().confirmed, 10000);
+    }
+
+    #[test]
+    fn fees() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let miner = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::Fixed(100)).unwrap();
+        let greedy = Transaction::new_coinbase_tx(&miner, "".to_string(), 5101).unwrap();
+        let block = next_block(&bc, vec![greedy, tx.clone()]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::CoinbaseTooLarge));
+
+        let genesis_coinbase = bc.get_block_by_height(0).unwrap().transactions[0].clone();
+        let inflating = spend(&genesis_coinbase, from, &to, 6000);
+        let coinbase = Transaction::new_coinbase_tx(&miner, "".to_string(), 5000).unwrap();
+        let block = next_block(&bc, vec![coinbase, inflating]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::OutputsExceedInputs(1)));
+
+        let coinbase = Transaction::new_coinbase_tx(&miner, "".to_string(), 5100).unwrap();
+        bc.mine_block(vec![coinbase, tx]).unwrap();
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 3900);
+        assert_eq!(bc.get_balance(&to).unwrap().confirmed, 1000);
+        assert_eq!(bc.get_balance(&miner).unwrap().confirmed, 5100);
+
+        let from = wallets.get(&address).unwrap();
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::Rate(2)).unwrap();
+        let fee = Fee::Rate(2).for_size(tx.size()).unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&miner, "".to_string(), 5000 + fee).unwrap();
+        bc.mine_block(vec![coinbase, tx]).unwrap();
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 2900 - fee);
+        assert_eq!(bc.get_balance(&miner).unwrap().confirmed, 10100 + fee);
+    }
+
+    #[test]
+    fn subsidy_halving() {
+        let (_dir, _wallets, mut bc, address) = test_chain();
+        let halving = bc.params().halving_interval;
+        for _ in 1..=halving {
+            let value = bc.coinbase_value(0);
+            let tx = Transaction::new_coinbase_tx(&address, "".to_string(), value).unwrap();
+            bc.mine_block(vec![tx]).unwrap();
+        }
+        assert_eq!(bc.total_supply(), halving * 5000 + 2500);
+        assert_eq!(bc.coinbase_value(10), 2510);
+
+        let greedy = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+        let block = next_block(&bc, vec![greedy]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::CoinbaseTooLarge));
+    }
+
+    #[test]
+    fn coinbase_maturity() {
+        let params = ChainParams {
+            coinbase_maturity: 3,
+            ..ChainParams::test()
+        };
+        let (_dir, mut wallets, mut bc, address) = test_chain_with(params);
+        let to = wallets.create_wallet().get_address();
+        let miner = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+
+        let balance = bc.get_balance(&address).unwrap();
+        assert_eq!((balance.confirmed, balance.immature), (0, 5000));
+        assert!(!balance.outs[0].mature);
+        assert!(bc.new_utxo_transaction(from, to.clone(), 1000, Fee::default()).is_err());
+        let genesis_coinbase = bc.get_block_by_height(0).unwrap().transactions[0].clone();
+        let early = spend(&genesis_coinbase, from, &to, 1000);
+        let coinbase = Transaction::new_coinbase_tx(&miner, "".to_string(), 5000).unwrap();
+        let block = next_block(&bc, vec![coinbase, early]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::ImmatureCoinbase(1)));
+
+        for _ in 0..2 {
+            let coinbase = Transaction::new_coinbase_tx(&miner, "".to_string(), 5000).unwrap();
+            bc.mine_block(vec![coinbase]).unwrap();
+        }
+        let balance = bc.get_balance(&address).unwrap();
+        assert_eq!((balance.confirmed, balance.immature), (5000, 0));
+        let from = wallets.get(&address).unwrap();
+        let tx = bc.new_utxo_transaction(from, to, 1000, Fee::default()).unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&miner, "".to_string(), 5000).unwrap();
+        bc.mine_block(vec![coinbase, tx]).unwrap();
+        let balance = bc.get_balance(&miner).unwrap();
+        assert_eq!((balance.confirmed, balance.immature), (5000, 10000));
+    }
+
+    #[test]
+    fn lock_times() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let coinbase = || Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+
+        // The next block has height 1
+        let payment = || TransactionBuilder::new(&bc, from).pay(&to, 1000);
+        let locked = payment().lock_time(1).build().unwrap();
+        assert!(!bc.is_final(&locked));
+        let block = next_block(&bc, vec![coinbase(), locked.clone()]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::NonFinalTransaction(1)));
+        assert_eq!(Mempool::new().add(&bc, locked), Err(MempoolError::NonFinal));
+        let later = payment().lock_time(current_timestamp() + 3600).build().unwrap();
+        assert!(!bc.is_final(&later));
+        assert!(bc.is_final(&payment().lock_time(current_timestamp() - 3600).build().unwrap()));
+
+        // Output the recipient can only spend with a lock time of at least 3
+        let genesis_coinbase = bc.get_block_by_height(0).unwrap().transactions[0].clone();
+        let outputs = vec![
+            TXOutput::time_locked(1000, 3, &to).unwrap(),
+            TXOutput::new(4000, &address).unwrap(),
+        ];
+        let vesting = unsigned_spend(&genesis_coinbase, 0, from, outputs);
+        let vesting = bc.sign_transaction(&vesting, &from.private_key()).unwrap();
+        bc.mine_block(vec![coinbase(), vesting.clone()]).unwrap();
+        assert!(bc.get_balance(&to).unwrap().outs.is_empty());
+
+        let recipient = wallets.get(&to).unwrap();
+        let claim = |bc: &Blockchain, lock_time: u64| {
+            let output = TXOutput::new(1000, &to).unwrap();
+            let tx = unsigned_spend(&vesting, 0, recipient, vec![output]).with_lock_time(lock_time);
+            bc.sign_transaction(&tx, &recipient.private_key()).unwrap()
+        };
+        let block = next_block(&bc, vec![coinbase(), claim(&bc, 1)]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::InvalidTransaction(1)));
+        let block = next_block(&bc, vec![coinbase(), claim(&bc, 3)]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::NonFinalTransaction(1)));
+
+        for _ in 0..2 {
+            bc.mine_block(vec![coinbase()]).unwrap();
+        }
+        let claim = claim(&bc, 3);
+        bc.mine_block(vec![coinbase(), claim]).unwrap();
+        assert_eq!(bc.get_balance(&to).unwrap().confirmed, 1000);
+    }
+
+    #[test]
+    fn relative_lock_times() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let coinbase = || Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+
+        // Output the recipient can only spend once it is two blocks deep
+        let genesis_coinbase = bc.get_block_by_height(0).unwrap().transactions[0].clone();
+        let outputs = vec![
+            TXOutput::age_locked(1000, 2, &to).unwrap(),
+            TXOutput::new(4000, &address).unwrap(),
+        ];
+        let funding = unsigned_spend(&genesis_coinbase, 0, from, outputs);
+        let funding = bc.sign_transaction(&funding, &from.private_key()).unwrap();
+        bc.mine_block(vec![coinbase(), funding.clone()]).unwrap();
+        assert!(bc.get_balance(&to).unwrap().outs.is_empty());
+
+        let recipient = wallets.get(&to).unwrap();
+        let claim = |bc: &Blockchain, sequence: u32| {
+            let input = TXInput {
+                sequence,
+                ..TXInput::new(&funding, 0, recipient)
+            };
+            let tx = Transaction::new(vec![input], vec![TXOutput::new(1000, &to).unwrap()]);
+            bc.sign_transaction(&tx, &recipient.private_key()).unwrap()
+        };
+        let block = next_block(&bc, vec![coinbase(), claim(&bc, 1)]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::InvalidTransaction(1)));
+        let block = next_block(&bc, vec![coinbase(), claim(&bc, 2)]);
+        assert_eq!(bc.validate_block(&block), Err(BlockError::SequenceLocked(1)));
+        assert_eq!(Mempool::new().add(&bc, claim(&bc, 2)), Err(MempoolError::NonFinal));
+
+        bc.mine_block(vec![coinbase()]).unwrap();
+        let claim = claim(&bc, 2);
+        assert_eq!(Mempool::new().add(&bc, claim.clone()), Ok(()));
+        bc.mine_block(vec![coinbase(), claim]).unwrap();
+        assert_eq!(bc.get_balance(&to).unwrap().confirmed, 1000);
+    }
+}
@@ -0,0 +1,59 @@
+use std::{error, fmt};
+
+use crate::blockchain::STORE_VERSION;
+use crate::mining_error::MiningError;
+
+/// Reason a chain store could not be opened.
+#[derive(Debug)]
+pub enum ChainError {
+    /// Named parameter is zero but divides heights or targets
+    InvalidParams(&'static str),
+    /// Store written in another format than `STORE_VERSION`, with its version if
+    /// it has one
+    StoreVersion(Option<u32>),
+    Genesis(MiningError),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChainError::InvalidParams(name) => {
+                write!(f, "chain parameter {} must not be zero", name)
+            }
+            ChainError::StoreVersion(Some(version)) => write!(
+                f,
+                "block store has format version {}, only {} can be read",
+                version, STORE_VERSION
+            ),
+            ChainError::StoreVersion(None) => write!(
+                f,
+                "block store predates format versions, only {} can be read",
+                STORE_VERSION
+            ),
+            ChainError::Genesis(ref e) => write!(f, "could not mine the genesis block: {}", e),
+        }
+    }
+}
+
+impl error::Error for ChainError {
+    fn description(&self) -> &str {
+        match *self {
+            ChainError::InvalidParams(_) => "invalid chain parameters",
+            ChainError::StoreVersion(_) => "unsupported block store format",
+            ChainError::Genesis(_) => "could not mine the genesis block",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            ChainError::Genesis(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MiningError> for ChainError {
+    fn from(error: MiningError) -> Self {
+        ChainError::Genesis(error)
+    }
+}
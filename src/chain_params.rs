@@ -0,0 +1,151 @@
+use crate::chain_error::ChainError;
+
+/// Consensus parameters of a network.
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    /// Difficulty of the genesis block and the easiest target allowed, in leading zero bits
+    pub pow_limit_bits: u64,
+    /// Number of blocks between two difficulty adjustments
+    pub retarget_interval: u64,
+    /// Desired time between blocks, in seconds
+    pub target_block_time: u64,
+    /// Maximum factor the target may change by in a single adjustment
+    pub max_adjustment: u64,
+    /// How far ahead of the local clock a block timestamp may be, in seconds
+    pub max_future_block_time: u64,
+    /// Number of previous blocks whose median timestamp a new block has to exceed
+    pub median_time_span: usize,
+    /// Subsidy of the coinbase before the first halving
+    pub initial_subsidy: u64,
+    /// Number of blocks after which the subsidy halves
+    pub halving_interval: u64,
+    /// Most coins the subsidies may ever create, the last subsidy is cut short to fit
+    pub max_supply: u64,
+    /// Number of blocks after its own a coinbase output can first be spent in
+    pub coinbase_maturity: u64,
+}
+
+impl ChainParams {
+    pub fn main() -> Self {
+        Self {
+            pow_limit_bits: 15,
+            retarget_interval: 100,
+            target_block_time: 10,
+            max_adjustment: 4,
+            max_future_block_time: 2 * 60 * 60,
+            median_time_span: 11,
+            initial_subsidy: 5000,
+            halving_interval: 210_000,
+            max_supply: 2_100_000_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    /// Cheap parameters for local chains and tests.
+    pub fn test() -> Self {
+        Self {
+            pow_limit_bits: 8,
+            retarget_interval: 5,
+            target_block_time: 1,
+            max_adjustment: 4,
+            max_future_block_time: 2 * 60 * 60,
+            median_time_span: 11,
+            initial_subsidy: 5000,
+            halving_interval: 10,
+            max_supply: 80_000,
+            coinbase_maturity: 1,
+        }
+    }
+
+    /// `InvalidParams` naming the first interval or factor that is zero.
+    pub fn validate(&self) -> Result<(), ChainError> {
+        let positive = [
+            ("retarget_interval", self.retarget_interval),
+            ("max_adjustment", self.max_adjustment),
+            ("halving_interval", self.halving_interval),
+        ];
+        match positive.iter().find(|(_, value)| *value == 0) {
+            Some((name, _)) => Err(ChainError::InvalidParams(name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Time the blocks of one retarget window are expected to take.
+    pub fn target_timespan(&self) -> u64 {
+        self.retarget_interval * self.target_block_time
+    }
+
+    /// New coins the coinbase of the block at `height` may create: the initial
+    /// subsidy halved once per `halving_interval` blocks, capped so the sum of all
+    /// subsidies never exceeds `max_supply`.
+    pub fn subsidy(&self, height: u64) -> u64 {
+        let scheduled = self.scheduled_subsidy(height);
+        scheduled.min(self.max_supply.saturating_sub(self.scheduled_supply(height)))
+    }
+
+    fn scheduled_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+
+    /// Sum of the scheduled subsidies of the blocks below `height`.
+    fn scheduled_supply(&self, height: u64) -> u64 {
+        let mut supply: u64 = 0;
+        for halvings in 0..64 {
+            let era_start = halvings * self.halving_interval;
+            if era_start >= height {
+                break;
+            }
+            let blocks = height.min(era_start + self.halving_interval) - era_start;
+            supply = supply.saturating_add(blocks.saturating_mul(self.initial_subsidy >> halvings));
+        }
+        supply
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::main()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chain_error::ChainError;
+
+    use super::ChainParams;
+
+    #[test]
+    fn subsidy_schedule() {
+        let params = ChainParams::test();
+        assert_eq!(params.subsidy(0), 5000);
+        assert_eq!(params.subsidy(9), 5000);
+        assert_eq!(params.subsidy(10), 2500);
+        assert_eq!(params.subsidy(23), 1250);
+        // 10 * 5000 + 10 * 2500 + 4 * 1250 reaches the cap
+        assert_eq!(params.subsidy(24), 0);
+
+        let total: u64 = (0..1000).map(|height| params.subsidy(height)).sum();
+        assert_eq!(total, params.max_supply);
+        let main = ChainParams::main();
+        assert_eq!(main.subsidy(210_000 * 64), 0);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(ChainParams::main().validate().is_ok());
+        let params = ChainParams {
+            retarget_interval: 0,
+            ..ChainParams::test()
+        };
+        assert!(matches!(params.validate(), Err(ChainError::InvalidParams("retarget_interval"))));
+        let params = ChainParams {
+            max_adjustment: 0,
+            ..ChainParams::test()
+        };
+        assert!(matches!(params.validate(), Err(ChainError::InvalidParams("max_adjustment"))));
+    }
+}
@@ -0,0 +1,209 @@
+use std::cmp::Reverse;
+
+use rand::seq::SliceRandom;
+
+use crate::transaction::OutPoint;
+
+/// Most branches `BranchAndBound` explores before giving up on an exact match.
+const MAX_TRIES: usize = 100_000;
+
+/// Outputs picked to fund a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    pub outputs: Vec<OutPoint>,
+    /// Value returned to the sender, the rest beyond the target goes to the fee
+    pub change: u64,
+}
+
+impl Selection {
+    fn new(outputs: Vec<OutPoint>, target: u64) -> Self {
+        let total: u64 = outputs.iter().map(|out| out.value).sum();
+        Selection {
+            outputs,
+            change: total - target,
+        }
+    }
+
+    pub fn input_count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.outputs.iter().map(|out| out.value).sum()
+    }
+}
+
+/// Strategy choosing which unspent outputs pay for a transaction.
+pub trait CoinSelector {
+    /// Picks outputs of `available` worth at least `target`, or `None` if all of
+    /// them together are not enough.
+    fn select(&self, available: &[OutPoint], target: u64) -> Option<Selection>;
+}
+
+/// Spends the biggest outputs first, keeping the input count low.
+pub struct LargestFirst;
+
+/// Spends the smallest outputs first, consolidating dust.
+pub struct SmallestFirst;
+
+/// Searches for a set of outputs matching the target exactly, or exceeding it by
+/// at most `tolerance` which is left to the fee, so no change output is needed.
+/// Falls back to `LargestFirst` when there is no such set.
+pub struct BranchAndBound {
+    pub tolerance: u64,
+}
+
+/// Spends outputs in random order, making it harder to link payments.
+pub struct RandomSelection;
+
+/// Takes outputs in the given order until they cover the target.
+fn accumulate(outputs: Vec<&OutPoint>, target: u64) -> Option<Selection> {
+    let mut selected = vec![];
+    let mut total: u64 = 0;
+    for out in outputs {
+        if total >= target && !selected.is_empty() {
+            break;
+        }
+        total += out.value;
+        selected.push(out.clone());
+    }
+    if total < target {
+        return None;
+    }
+    Some(Selection::new(selected, target))
+}
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, available: &[OutPoint], target: u64) -> Option<Selection> {
+        let mut outputs: Vec<&OutPoint> = available.iter().collect();
+        outputs.sort_by_key(|out| Reverse(out.value));
+        accumulate(outputs, target)
+    }
+}
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, available: &[OutPoint], target: u64) -> Option<Selection> {
+        let mut outputs: Vec<&OutPoint> = available.iter().collect();
+        outputs.sort_by_key(|out| out.value);
+        accumulate(outputs, target)
+    }
+}
+
+impl CoinSelector for RandomSelection {
+    fn select(&self, available: &[OutPoint], target: u64) -> Option<Selection> {
+        let mut outputs: Vec<&OutPoint> = available.iter().collect();
+        outputs.shuffle(&mut rand::thread_rng());
+        accumulate(outputs, target)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, available: &[OutPoint], target: u64) -> Option<Selection> {
+        let mut outputs: Vec<&OutPoint> = available.iter().collect();
+        outputs.sort_by_key(|out| Reverse(out.value));
+        // remaining[i] is the value of outputs[i..], used to prune hopeless branches
+        let mut remaining = vec![0; outputs.len() + 1];
+        for i in (0..outputs.len()).rev() {
+            remaining[i] = remaining[i + 1] + outputs[i].value;
+        }
+
+        let mut search = Search {
+            outputs: &outputs,
+            remaining: &remaining,
+            target,
+            upper: target.saturating_add(self.tolerance),
+            tries: 0,
+            chosen: vec![],
+        };
+        if search.run(0, 0) {
+            let selected = search.chosen.iter().map(|&i| outputs[i].clone()).collect();
+            return Some(Selection {
+                outputs: selected,
+                change: 0,
+            });
+        }
+        LargestFirst.select(available, target)
+    }
+}
+
+/// Depth-first search over including or skipping each output, largest first.
+struct Search<'a> {
+    outputs: &'a [&'a OutPoint],
+    remaining: &'a [u64],
+    target: u64,
+    upper: u64,
+    tries: usize,
+    chosen: Vec<usize>,
+}
+
+impl<'a> Search<'a> {
+    fn run(&mut self, idx: usize, sum: u64) -> bool {
+        if sum >= self.target {
+            return sum <= self.upper;
+        }
+        self.tries += 1;
+        if idx == self.outputs.len()
+            || sum + self.remaining[idx] < self.target
+            || self.tries > MAX_TRIES
+        {
+            return false;
+        }
+        let value = self.outputs[idx].value;
+        if sum + value <= self.upper {
+            self.chosen.push(idx);
+            if self.run(idx + 1, sum + value) {
+                return true;
+            }
+            self.chosen.pop();
+        }
+        self.run(idx + 1, sum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Sha256Hash;
+    use crate::transaction::OutPoint;
+
+    use super::{BranchAndBound, CoinSelector, LargestFirst, RandomSelection, SmallestFirst};
+
+    fn outputs(values: &[u64]) -> Vec<OutPoint> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, &value)| OutPoint {
+                tx_id: Sha256Hash::default(),
+                vout: vout as i64,
+                value,
+                height: 0,
+                mature: true,
+            })
+            .collect()
+    }
+
+    fn values(selector: &dyn CoinSelector, available: &[OutPoint], target: u64) -> Vec<u64> {
+        let selection = selector.select(available, target).unwrap();
+        assert_eq!(selection.change + target, selection.total());
+        selection.outputs.iter().map(|out| out.value).collect()
+    }
+
+    #[test]
+    fn strategies() {
+        let available = outputs(&[1000, 3000, 500, 2000]);
+        assert_eq!(values(&LargestFirst, &available, 2500), vec![3000]);
+        assert_eq!(values(&SmallestFirst, &available, 2500), vec![500, 1000, 2000]);
+        let exact = BranchAndBound { tolerance: 0 };
+        assert_eq!(values(&exact, &available, 2500), vec![2000, 500]);
+        assert_eq!(exact.select(&available, 2500).unwrap().change, 0);
+        // No exact match, falls back to the largest outputs
+        assert_eq!(values(&exact, &available, 2600), vec![3000]);
+        let loose = BranchAndBound { tolerance: 100 };
+        let selection = loose.select(&available, 2450).unwrap();
+        assert_eq!((selection.input_count(), selection.change), (2, 0));
+
+        let random = RandomSelection.select(&available, 6001).unwrap();
+        assert_eq!((random.input_count(), random.change), (4, 499));
+        assert!(LargestFirst.select(&available, 6501).is_none());
+        assert!(exact.select(&available, 6501).is_none());
+    }
+}
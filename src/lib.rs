@@ -5,12 +5,22 @@ extern crate serde_big_array;
 extern crate serde_derive;
 
 pub mod blockchain;
+pub mod chain_params;
+pub mod coin_selection;
 pub mod wallet;
 mod block;
+pub mod block_error;
+pub mod chain_error;
+pub mod mempool;
+pub mod mempool_error;
 mod mining_error;
 mod proof_of_work;
 mod store;
+pub mod tx_builder;
+mod tx_index;
 mod utxo;
 mod merkel_tree;
-mod script_lang;
+pub mod script_lang;
 pub mod transaction;
+#[cfg(test)]
+mod test_utils;
@@ -2,20 +2,64 @@ extern crate structopt;
 
 
 
+use rustc_serialize::hex::ToHex;
 use structopt::StructOpt;
 
 use blockchain::blockchain::Blockchain;
-use blockchain::transaction::Transaction;
-use blockchain::wallet::{Wallets};
+use blockchain::coin_selection::{
+    BranchAndBound, CoinSelector, LargestFirst, RandomSelection, SmallestFirst,
+};
+use blockchain::mempool::Mempool;
+use blockchain::script_lang::ScriptPubKey;
+use blockchain::transaction::{Fee, Transaction};
+use blockchain::tx_builder::TransactionBuilder;
+use blockchain::wallet::{Address, Wallets};
+
+/// Most pending transactions put into a single mined block
+const MAX_BLOCK_TRANSACTIONS: usize = 1000;
 
 #[derive(StructOpt)]
 struct Send {
     #[structopt(default_value = "test")]
     from: String,
-    #[structopt(default_value = "test")]
-    to: String,
-    #[structopt(default_value = "0")]
-    amount: u64,
+    /// Recipient and amount as `address:amount`, may be repeated
+    #[structopt(long = "to", parse(try_from_str = "parse_payment"))]
+    to: Vec<(String, u64)>,
+    /// Output locked by a custom script as `asm:amount`, e.g.
+    /// `"OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG:10"`, may be repeated
+    #[structopt(long = "script", parse(try_from_str = "parse_script_payment"))]
+    script: Vec<(ScriptPubKey, u64)>,
+    /// Address receiving the change instead of the sender
+    #[structopt(long = "change", parse(try_from_str = "parse_address"))]
+    change: Option<String>,
+    /// Fixed fee paid to the miner
+    #[structopt(long = "fee")]
+    fee: Option<u64>,
+    /// Fee paid per byte of the transaction, used when no fixed fee is given
+    #[structopt(long = "fee-rate")]
+    fee_rate: Option<u64>,
+    /// Coin selection strategy: largest, smallest, exact or random
+    #[structopt(
+        long = "coins",
+        default_value = "largest",
+        parse(try_from_str = "parse_selector")
+    )]
+    coins: Box<dyn CoinSelector>,
+    /// Height, or unix time from 500000000 on, the transaction may not be mined before
+    #[structopt(long = "lock-time", default_value = "0")]
+    lock_time: u64,
+    /// Mine a block with every pending transaction right away, rewarding the sender
+    #[structopt(short = "m", long = "mine")]
+    mine: bool,
+}
+
+#[derive(StructOpt)]
+struct Opt {
+    /// Number of threads used to mine blocks
+    #[structopt(short = "t", long = "threads", default_value = "1")]
+    threads: usize,
+    #[structopt(subcommand)]
+    cmd: Cli,
 }
 
 #[derive(StructOpt)]
@@ -24,6 +68,9 @@ enum Cli {
     Send(Send),
     #[structopt(name = "coinbase")]
     Coinbase { to: String },
+    /// Mine a block with the pending transactions
+    #[structopt(name = "mine")]
+    Mine { to: String },
     #[structopt(name = "balance")]
     Balance { of: String },
     #[structopt(name = "address")]
@@ -43,30 +90,75 @@ fn main() {
             blockchain
         }
         Err(e) => {
-            println!("{:?}", e);
+            println!("{}", e);
             return;
         }
     };
-    match Cli::from_args() {
+    let opt = Opt::from_args();
+    bc.set_mining_threads(opt.threads);
+    let mut mempool = Mempool::load(&bc);
+    match opt.cmd {
         Cli::Send(cmd) => {
             let wallet = match wallets.get(&cmd.from) {
                 Some(t) => t,
                 None => panic!("Wallet not find"),
             };
-            let tx = bc.new_utxo_transaction(wallet, cmd.to, cmd.amount).unwrap();
-            match bc.mine_block(vec![tx]) {
-                Ok(_) => println!("Block successfully add"),
-                Err(e) => println!("{}", e),
+            let fee = match (cmd.fee, cmd.fee_rate) {
+                (Some(fee), _) => Fee::Fixed(fee),
+                (None, Some(rate)) => Fee::Rate(rate),
+                (None, None) => Fee::default(),
+            };
+            let mut builder = TransactionBuilder::new(&bc, wallet)
+                .fee(fee)
+                .coin_selector(cmd.coins)
+                .lock_time(cmd.lock_time);
+            for (address, amount) in &cmd.to {
+                builder = builder.pay(address, *amount);
+            }
+            for (script, amount) in &cmd.script {
+                builder = builder.pay_to_script(script.clone(), *amount);
+            }
+            if let Some(ref change) = cmd.change {
+                builder = builder.change_address(change);
+            }
+            let tx = match builder.build() {
+                Ok(tx) => tx,
+                Err(e) => {
+                    println!("{:?}", e);
+                    return;
+                }
+            };
+            let tx_id = tx.id;
+            match mempool.add(&bc, tx) {
+                Ok(()) => println!("Transaction {} added to the mempool", tx_id.to_hex()),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            mempool.save(&bc);
+            if cmd.mine {
+                mine_pending(&mut bc, &mut mempool, &cmd.from);
+            }
+        }
+        Cli::Mine { to } => {
+            if wallets.get(&to).is_none() {
+                panic!("Wallet not found")
             }
+            mine_pending(&mut bc, &mut mempool, &to);
         }
         Cli::Coinbase { to } => {
             if wallets.get(&to).is_none() {
                 panic!("Wallet not found")
             }
 
-            let tx = Transaction::new_coinbase_tx(&to, "".to_string());
+            let tx = Transaction::new_coinbase_tx(&to, "".to_string(), bc.coinbase_value(0))
+                .expect("wallet address");
             match bc.mine_block(vec![tx]) {
-                Ok(_) => println!("Block successfully add"),
+                Ok(stats) => println!(
+                    "Block successfully add, {:.0} hashes per second",
+                    stats.hashes_per_second()
+                ),
                 Err(e) => println!("{}", e),
             }
         }
@@ -76,7 +168,27 @@ fn main() {
             }
         }
         Cli::Balance { of } => {
-            println!("Balance of {} is {}", &of, bc.get_balance(&of));
+            let balance = match bc.get_balance(&of) {
+                Ok(balance) => balance,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            println!("Balance of {} is {}", &of, balance.confirmed);
+            if balance.immature > 0 {
+                println!("Immature coinbase rewards {}", balance.immature);
+            }
+            for out in balance.outs {
+                println!(
+                    "  {}:{} value {} at height {}{}",
+                    out.tx_id.to_hex(),
+                    out.vout,
+                    out.value,
+                    out.height,
+                    if out.mature { "" } else { " (immature)" }
+                );
+            }
         }
         Cli::Address => {
             let wallet = wallets.create_wallet();
@@ -84,3 +196,59 @@ fn main() {
         }
     }
 }
+
+fn parse_payment(payment: &str) -> Result<(String, u64), String> {
+    let mut parts = payment.rsplitn(2, ':');
+    let amount = parts.next().unwrap();
+    let address = parts
+        .next()
+        .ok_or_else(|| format!("expected address:amount, got {}", payment))?;
+    let amount = amount.parse::<u64>().map_err(|e| e.to_string())?;
+    Ok((parse_address(address)?, amount))
+}
+
+fn parse_address(address: &str) -> Result<String, String> {
+    match Address::decode(address) {
+        Some(_) => Ok(address.to_string()),
+        None => Err(format!("invalid address {}", address)),
+    }
+}
+
+fn parse_script_payment(payment: &str) -> Result<(ScriptPubKey, u64), String> {
+    let (script, amount) = payment
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected asm:amount, got {}", payment))?;
+    let script = script.parse::<ScriptPubKey>().map_err(|e| e.to_string())?;
+    let amount = amount.parse::<u64>().map_err(|e| e.to_string())?;
+    Ok((script, amount))
+}
+
+fn parse_selector(name: &str) -> Result<Box<dyn CoinSelector>, String> {
+    match name {
+        "largest" => Ok(Box::new(LargestFirst)),
+        "smallest" => Ok(Box::new(SmallestFirst)),
+        "exact" => Ok(Box::new(BranchAndBound { tolerance: 0 })),
+        "random" => Ok(Box::new(RandomSelection)),
+        _ => Err(format!("unknown coin selection strategy {}", name)),
+    }
+}
+
+/// Mines the pool's block template and drops what got confirmed from the pool.
+fn mine_pending(bc: &mut Blockchain, mempool: &mut Mempool, to: &str) {
+    let events = bc.subscribe();
+    let template = mempool.block_template(MAX_BLOCK_TRANSACTIONS);
+    let fees = template.iter().filter_map(|tx| mempool.fee(&tx.id)).sum();
+    let coinbase = Transaction::new_coinbase_tx(to, "".to_string(), bc.coinbase_value(fees))
+        .expect("wallet address");
+    let mut transactions = vec![coinbase];
+    transactions.extend(template);
+    match bc.mine_block(transactions) {
+        Ok(stats) => println!(
+            "Block successfully add, {:.0} hashes per second",
+            stats.hashes_per_second()
+        ),
+        Err(e) => println!("{}", e),
+    }
+    mempool.update(bc, events.try_iter());
+    mempool.save(bc);
+}
@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+
+use rkv::{StoreOptions, Value};
+use rustc_serialize::hex::ToHex;
+
+use crate::block::Sha256Hash;
+use crate::blockchain::{Blockchain, ChainEvent};
+use crate::mempool_error::MempoolError;
+use crate::transaction::Transaction;
+use crate::utxo::UTXOSet;
+
+const MEMPOOLBUCKET: &str = "mempool";
+
+struct PoolEntry {
+    tx: Transaction,
+    /// Arrival order, keeps block templates stable
+    sequence: u64,
+    fee: u64,
+    /// Pool transactions whose outputs this one spends
+    parents: HashSet<Sha256Hash>,
+}
+
+/// Unconfirmed transactions waiting to be mined. Every entry spends outputs that
+/// are either unspent on the main chain or created by another entry, and no
+/// output is spent by two entries.
+#[derive(Default)]
+pub struct Mempool {
+    entries: HashMap<Sha256Hash, PoolEntry>,
+    /// Outputs spent by pool entries, mapped to the spending transaction
+    spends: HashMap<(Sha256Hash, i64), Sha256Hash>,
+    next_sequence: u64,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the transactions written by `save` and validates them again, dropping
+    /// the ones the chain confirmed or invalidated in the meantime.
+    pub fn load(blockchain: &Blockchain) -> Self {
+        let txs: Vec<Transaction> = {
+            let rkv = blockchain.store.rkv();
+            let store = rkv.open_single(MEMPOOLBUCKET, StoreOptions::create()).unwrap();
+            let reader = rkv.read().unwrap();
+            match store.get(&reader, "transactions").unwrap() {
+                Some(Value::Blob(val)) => bincode::deserialize(val).unwrap(),
+                Some(_) => panic!("Wrong format"),
+                None => vec![],
+            }
+        };
+        let mut mempool = Self::new();
+        for tx in txs {
+            let _ = mempool.add(blockchain, tx);
+        }
+        mempool
+    }
+
+    pub fn save(&self, blockchain: &Blockchain) {
+        let txs = self.block_template(self.len());
+        let rkv = blockchain.store.rkv();
+        let store = rkv.open_single(MEMPOOLBUCKET, StoreOptions::create()).unwrap();
+        let mut writer = rkv.write().unwrap();
+        store
+            .put(&mut writer, "transactions", &Value::Blob(&bincode::serialize(&txs).unwrap()))
+            .unwrap();
+        writer.commit().unwrap();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, tx_id: &Sha256Hash) -> bool {
+        self.entries.contains_key(tx_id)
+    }
+
+    pub fn get(&self, tx_id: &Sha256Hash) -> Option<&Transaction> {
+        self.entries.get(tx_id).map(|entry| &entry.tx)
+    }
+
+    /// Fee paid by a pool transaction.
+    pub fn fee(&self, tx_id: &Sha256Hash) -> Option<u64> {
+        self.entries.get(tx_id).map(|entry| entry.fee)
+    }
+
+    /// Validates `tx` against the main chain and the pool and queues it for mining.
+    /// Inputs may spend outputs of other pool transactions, which become its parents.
+    pub fn add(&mut self, blockchain: &Blockchain, tx: Transaction) -> Result<(), MempoolError> {
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        if self.contains(&tx.id) || blockchain.find_transaction(&tx.id).is_some() {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if !blockchain.is_final(&tx) {
+            return Err(MempoolError::NonFinal);
+        }
+
+        let utxo_set = UTXOSet::new(blockchain);
+        let mut parents: HashSet<Sha256Hash> = HashSet::new();
+        let mut spent: HashSet<(Sha256Hash, i64)> = HashSet::new();
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        for (idx, vin) in tx.vin.iter().enumerate() {
+            if !spent.insert((vin.tx_id, vin.vout)) {
+                return Err(MempoolError::DuplicateInput(idx));
+            }
+            if let Some(other) = self.spends.get(&(vin.tx_id, vin.vout)) {
+                return Err(MempoolError::Conflict(*other));
+            }
+            let prev_tx = match self.entries.get(&vin.tx_id) {
+                Some(parent) => {
+                    if vin.sequence > 0 {
+                        return Err(MempoolError::NonFinal);
+                    }
+                    parents.insert(vin.tx_id);
+                    parent.tx.clone()
+                }
+                None => {
+                    if utxo_set.get_output(&vin.tx_id, vin.vout).is_none() {
+                        return Err(MempoolError::MissingInput(idx));
+                    }
+                    let (prev_tx, height) = blockchain
+                        .locate_transaction(&vin.tx_id)
+                        .ok_or(MempoolError::MissingInput(idx))?;
+                    if prev_tx.is_coinbase()
+                        && !blockchain.coinbase_matures(height, blockchain.height() + 1)
+                    {
+                        return Err(MempoolError::ImmatureCoinbase(idx));
+                    }
+                    if blockchain.height() + 1 < height.saturating_add(vin.sequence as u64) {
+                        return Err(MempoolError::NonFinal);
+                    }
+                    prev_tx
+                }
+            };
+            if vin.vout < 0 || vin.vout as usize >= prev_tx.vout.len() {
+                return Err(MempoolError::MissingInput(idx));
+            }
+            prev_txs.insert(vin.tx_id.to_hex(), prev_tx);
+        }
+        if !tx.verify(&prev_txs) {
+            return Err(MempoolError::InvalidTransaction);
+        }
+        let fee = tx.fee(&prev_txs).ok_or(MempoolError::OutputsExceedInputs)?;
+
+        for vin in &tx.vin {
+            self.spends.insert((vin.tx_id, vin.vout), tx.id);
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let entry = PoolEntry {
+            tx,
+            sequence,
+            fee,
+            parents,
+        };
+        self.entries.insert(entry.tx.id, entry);
+        Ok(())
+    }
+
+    /// Pool transactions `tx_id` spends outputs of.
+    pub fn parents(&self, tx_id: &Sha256Hash) -> Vec<Sha256Hash> {
+        self.entries
+            .get(tx_id)
+            .map(|entry| entry.parents.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Pool transactions spending outputs of `tx_id`.
+    pub fn children(&self, tx_id: &Sha256Hash) -> Vec<Sha256Hash> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.parents.contains(tx_id))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Removes a transaction together with every pool transaction depending on it
+    /// and returns them.
+    pub fn remove(&mut self, tx_id: &Sha256Hash) -> Vec<Transaction> {
+        let mut removed = vec![];
+        let mut pending = vec![*tx_id];
+        while let Some(id) = pending.pop() {
+            if let Some(entry) = self.remove_entry(&id) {
+                pending.extend(self.children(&id));
+                removed.push(entry.tx);
+            }
+        }
+        removed
+    }
+
+    fn remove_entry(&mut self, tx_id: &Sha256Hash) -> Option<PoolEntry> {
+        let entry = self.entries.remove(tx_id)?;
+        for vin in &entry.tx.vin {
+            self.spends.remove(&(vin.tx_id, vin.vout));
+        }
+        Some(entry)
+    }
+
+    /// Follows a change of the main chain, given every event since the last update.
+    /// Transactions confirmed by a connected block leave the pool along with
+    /// everything conflicting with them; those of a disconnected block are offered
+    /// to the pool again, and pool transactions spending outputs that no longer
+    /// exist are dropped. A reorganization disconnects the tip first, so its blocks
+    /// are offered lowest first to return parents before their children.
+    pub fn update<I>(&mut self, blockchain: &Blockchain, events: I)
+    where
+        I: IntoIterator<Item = ChainEvent>,
+    {
+        let mut disconnected: Vec<Sha256Hash> = vec![];
+        for event in events {
+            match event {
+                ChainEvent::BlockConnected { hash, .. } => {
+                    for hash in disconnected.drain(..).rev() {
+                        self.block_disconnected(blockchain, &hash);
+                    }
+                    self.block_connected(blockchain, &hash);
+                }
+                ChainEvent::BlockDisconnected { hash, .. } => disconnected.push(hash),
+            }
+        }
+        for hash in disconnected.iter().rev() {
+            self.block_disconnected(blockchain, hash);
+        }
+    }
+
+    fn block_connected(&mut self, blockchain: &Blockchain, hash: &Sha256Hash) {
+        let block = blockchain.get_block_by_hash(hash).unwrap();
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            if self.remove_entry(&tx.id).is_some() {
+                for child in self.children(&tx.id) {
+                    self.entries.get_mut(&child).unwrap().parents.remove(&tx.id);
+                }
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(other) = self.spends.get(&(vin.tx_id, vin.vout)).cloned() {
+                    self.remove(&other);
+                }
+            }
+        }
+    }
+
+    fn block_disconnected(&mut self, blockchain: &Blockchain, hash: &Sha256Hash) {
+        let block = blockchain.get_block_by_hash(hash).unwrap();
+        for tx in block.transactions {
+            if blockchain.find_transaction(&tx.id).is_some() {
+                continue;
+            }
+            let tx_id = tx.id;
+            let returned = !tx.is_coinbase() && self.add(blockchain, tx).is_ok();
+            let spenders: Vec<Sha256Hash> = self
+                .spends
+                .iter()
+                .filter(|((spent_id, _), _)| *spent_id == tx_id)
+                .map(|(_, spender)| *spender)
+                .collect();
+            for spender in spenders {
+                if returned {
+                    self.entries.get_mut(&spender).unwrap().parents.insert(tx_id);
+                } else {
+                    self.remove(&spender);
+                }
+            }
+        }
+    }
+
+    /// Up to `max_txs` pool transactions for the next block, oldest first, with
+    /// every transaction placed after the pool transactions it spends from.
+    pub fn block_template(&self, max_txs: usize) -> Vec<Transaction> {
+        let mut pending: Vec<&PoolEntry> = self.entries.values().collect();
+        pending.sort_by_key(|entry| entry.sequence);
+        let mut selected: HashSet<Sha256Hash> = HashSet::new();
+        let mut template = vec![];
+        while template.len() < max_txs {
+            let ready = pending
+                .iter()
+                .position(|entry| entry.parents.iter().all(|parent| selected.contains(parent)));
+            match ready {
+                Some(idx) => {
+                    let entry = pending.remove(idx);
+                    selected.insert(entry.tx.id);
+                    template.push(entry.tx.clone());
+                }
+                None => break,
+            }
+        }
+        template
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mempool_error::MempoolError;
+    use crate::test_utils::{block_on, spend, test_chain};
+    use crate::transaction::{Fee, Transaction};
+
+    use super::Mempool;
+
+    #[test]
+    fn add_and_mine() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let mut mempool = Mempool::new();
+
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::default()).unwrap();
+        let conflicting = bc.new_utxo_transaction(from, to.clone(), 2000, Fee::default()).unwrap();
+        mempool.add(&bc, tx.clone()).unwrap();
+        assert_eq!(mempool.add(&bc, tx.clone()), Err(MempoolError::AlreadyKnown));
+        assert_eq!(mempool.add(&bc, conflicting), Err(MempoolError::Conflict(tx.id)));
+        let coinbase = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+        assert_eq!(mempool.add(&bc, coinbase.clone()), Err(MempoolError::Coinbase));
+
+        let child = spend(&tx, wallets.get(&to).unwrap(), &address, 1000);
+        mempool.add(&bc, child.clone()).unwrap();
+        assert_eq!(mempool.parents(&child.id), vec![tx.id]);
+        assert_eq!(mempool.children(&tx.id), vec![child.id]);
+        let template: Vec<_> = mempool.block_template(10).iter().map(|tx| tx.id).collect();
+        assert_eq!(template, vec![tx.id, child.id]);
+        assert_eq!(mempool.block_template(1).len(), 1);
+
+        let events = bc.subscribe();
+        let mut transactions = vec![coinbase];
+        transactions.extend(mempool.block_template(10));
+        bc.mine_block(transactions).unwrap();
+        mempool.update(&bc, events.try_iter());
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.add(&bc, tx), Err(MempoolError::AlreadyKnown));
+    }
+
+    #[test]
+    fn confirmed_conflict_evicts_descendants() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let mut mempool = Mempool::new();
+
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::default()).unwrap();
+        let conflicting = bc.new_utxo_transaction(from, to.clone(), 2000, Fee::default()).unwrap();
+        mempool.add(&bc, tx.clone()).unwrap();
+        let child = spend(&tx, wallets.get(&to).unwrap(), &address, 1000);
+        mempool.add(&bc, child).unwrap();
+        mempool.save(&bc);
+        assert_eq!(Mempool::load(&bc).len(), 2);
+
+        let events = bc.subscribe();
+        let coinbase = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+        bc.mine_block(vec![coinbase, conflicting]).unwrap();
+        mempool.update(&bc, events.try_iter());
+        assert!(mempool.is_empty());
+        assert!(Mempool::load(&bc).is_empty());
+    }
+
+    #[test]
+    fn reorganization_returns_transactions() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let miner = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+        let coinbase = |to: &str| Transaction::new_coinbase_tx(to, "".to_string(), 5000).unwrap();
+        let genesis = bc.get_block_by_height(0).unwrap();
+
+        // The parent and the child are confirmed in consecutive blocks
+        let tx = bc.new_utxo_transaction(from, to.clone(), 1000, Fee::default()).unwrap();
+        bc.mine_block(vec![coinbase(&address), tx.clone()]).unwrap();
+        let child = spend(&tx, wallets.get(&to).unwrap(), &address, 1000);
+        bc.mine_block(vec![coinbase(&address), child.clone()]).unwrap();
+
+        let events = bc.subscribe();
+        let mut parent = genesis;
+        for _ in 0..3 {
+            let block = block_on(&bc, &parent, vec![coinbase(&miner)]);
+            bc.add_block(block.clone()).unwrap();
+            parent = block;
+        }
+        assert_eq!(bc.height(), 3);
+
+        let mut mempool = Mempool::new();
+        mempool.update(&bc, events.try_iter());
+        assert!(mempool.contains(&tx.id));
+        assert!(mempool.contains(&child.id));
+        assert_eq!(mempool.parents(&child.id), vec![tx.id]);
+    }
+}
@@ -0,0 +1,66 @@
+use std::{error, fmt};
+
+use rustc_serialize::hex::ToHex;
+
+use crate::block::Sha256Hash;
+
+/// Reason a transaction was not accepted into the mempool. Input level variants
+/// carry the index of the offending input.
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    Coinbase,
+    AlreadyKnown,
+    DuplicateInput(usize),
+    MissingInput(usize),
+    ImmatureCoinbase(usize),
+    Conflict(Sha256Hash),
+    InvalidTransaction,
+    OutputsExceedInputs,
+    NonFinal,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MempoolError::Coinbase => write!(f, "coinbase transactions are only valid in blocks"),
+            MempoolError::AlreadyKnown => write!(f, "transaction is already known"),
+            MempoolError::DuplicateInput(idx) => {
+                write!(f, "input {} spends an output already spent by the transaction", idx)
+            }
+            MempoolError::MissingInput(idx) => {
+                write!(f, "input {} spends an unknown or spent output", idx)
+            }
+            MempoolError::ImmatureCoinbase(idx) => {
+                write!(f, "input {} spends a coinbase output before it matured", idx)
+            }
+            MempoolError::Conflict(ref tx_id) => {
+                write!(f, "transaction conflicts with pool transaction {}", tx_id.to_hex())
+            }
+            MempoolError::InvalidTransaction => write!(f, "transaction failed verification"),
+            MempoolError::OutputsExceedInputs => {
+                write!(f, "transaction spends more than its inputs")
+            }
+            MempoolError::NonFinal => write!(f, "transaction is locked past the next block"),
+        }
+    }
+}
+
+impl error::Error for MempoolError {
+    fn description(&self) -> &str {
+        match *self {
+            MempoolError::Coinbase => "coinbase transactions are only valid in blocks",
+            MempoolError::AlreadyKnown => "transaction is already known",
+            MempoolError::DuplicateInput(_) => "output spent twice by the transaction",
+            MempoolError::MissingInput(_) => "input spends an unknown or spent output",
+            MempoolError::ImmatureCoinbase(_) => "coinbase output spent before it matured",
+            MempoolError::Conflict(_) => "transaction conflicts with a pool transaction",
+            MempoolError::InvalidTransaction => "transaction failed verification",
+            MempoolError::OutputsExceedInputs => "transaction spends more than its inputs",
+            MempoolError::NonFinal => "transaction is locked past the next block",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
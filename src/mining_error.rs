@@ -1,20 +1,22 @@
 use std::{error, fmt};
 
+use crate::block_error::BlockError;
+
 #[derive(Debug)]
 pub enum MiningError {
     Iteration,
+    Cancelled,
     NoParent,
+    InvalidBlock(BlockError),
 }
 
 impl fmt::Display for MiningError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            MiningError::Iteration => write!(
-                f,
-                "could
-        not mine block, hit iteration limit"
-            ),
+            MiningError::Iteration => write!(f, "could not mine block, nonce space exhausted"),
+            MiningError::Cancelled => write!(f, "mining was cancelled"),
             MiningError::NoParent => write!(f, "block has no parent"),
+            MiningError::InvalidBlock(ref e) => write!(f, "mined block is invalid: {}", e),
         }
     }
 }
@@ -22,12 +24,17 @@ impl fmt::Display for MiningError {
 impl error::Error for MiningError {
     fn description(&self) -> &str {
         match *self {
-            MiningError::Iteration => "could not mine block, hit iteration limit",
+            MiningError::Iteration => "could not mine block, nonce space exhausted",
+            MiningError::Cancelled => "mining was cancelled",
             MiningError::NoParent => "block has no parent",
+            MiningError::InvalidBlock(_) => "mined block is invalid",
         }
     }
 
     fn cause(&self) -> Option<&dyn error::Error> {
-        None
+        match *self {
+            MiningError::InvalidBlock(ref e) => Some(e),
+            _ => None,
+        }
     }
 }
@@ -1,55 +1,203 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use num_bigint::BigUint;
 use num_bigint::ToBigUint;
 
 use crate::block::{Block, Sha256Hash};
+use crate::chain_params::ChainParams;
 use crate::mining_error::MiningError;
 
-pub(crate) const TARGET_BITS: u64 = 15;
-const MAX_NONCE: u64 = 1_000_000;
+/// How many nonces are tried between two checks of the stop flags.
+const CANCEL_CHECK_INTERVAL: u64 = 1 << 12;
 
 pub struct ProofOfWork<'a> {
     block: &'a Block,
     target: BigUint,
+    /// Hasher that already absorbed every header field except the nonce
+    prefix: Sha256,
+}
+
+/// Work done while mining a block, summed over every worker and extra-nonce round.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    pub fn hashes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.hashes as f64 / seconds
+    }
 }
 
 impl<'a> ProofOfWork<'a> {
-    pub fn new(block: &Block) -> ProofOfWork {
-        let target = 1_u64.to_biguint().unwrap();
-        let shift_target: BigUint = target << (256 - TARGET_BITS) as usize;
+    pub fn new(block: &'a Block) -> ProofOfWork<'a> {
+        let mut prefix = Sha256::new();
+        prefix.input(&block.headers());
         ProofOfWork {
             block,
-            target: shift_target,
+            target: BigUint::from_bytes_be(&block.target),
+            prefix,
         }
     }
 
-    pub fn validate(&self) -> bool {
-        let hash = self.calculate_hash(self.block.nonce);
+    /// Checks that the header commits to `expected_target`, that the stored hash is the
+    /// hash of the header and that the nonce satisfies the target.
+    pub fn validate(&self, expected_target: &BigUint) -> bool {
+        if self.target != *expected_target {
+            return false;
+        }
+        let hash = calculate_hash(self.prefix, self.block.nonce);
+        if hash != self.block.hash {
+            return false;
+        }
         let hash_int = BigUint::from_bytes_be(&hash);
         hash_int <= self.target
     }
 
-    pub fn run(&self) -> Result<(u64, Sha256Hash), MiningError> {
-        for nonce in 0..MAX_NONCE {
-            let hash = self.calculate_hash(nonce);
-            let hash_int = BigUint::from_bytes_be(&hash);
-            if hash_int <= self.target {
-                return Ok((nonce, hash));
+    /// Splits the whole nonce space into `threads` contiguous ranges and searches them
+    /// in parallel, stopping every worker as soon as one finds a solution. Fails with
+    /// `MiningError::Iteration` once every nonce was tried, so the caller can change
+    /// the header and start over, and with `MiningError::Cancelled` as soon as `cancel`
+    /// is set from another thread. The work done is added to `stats`.
+    pub fn run(
+        &self,
+        threads: usize,
+        cancel: &AtomicBool,
+        stats: &mut MiningStats,
+    ) -> Result<(u64, Sha256Hash), MiningError> {
+        let threads = threads.max(1) as u64;
+        let chunk = u64::MAX / threads;
+        let found = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let started = Instant::now();
+
+        let solution = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|worker| {
+                    let first = worker * chunk;
+                    let last = if worker == threads - 1 {
+                        u64::MAX
+                    } else {
+                        first + chunk - 1
+                    };
+                    let search = Search {
+                        prefix: self.prefix,
+                        target: self.block.target,
+                        found: &found,
+                        cancel,
+                        hashes: &hashes,
+                    };
+                    scope.spawn(move || search.run(first, last))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .filter_map(|worker| worker.join().unwrap())
+                .next()
+        });
+
+        stats.hashes += hashes.load(Ordering::Relaxed);
+        stats.elapsed += started.elapsed();
+        match solution {
+            Some(solution) => Ok(solution),
+            None if cancel.load(Ordering::Relaxed) => Err(MiningError::Cancelled),
+            None => Err(MiningError::Iteration),
+        }
+    }
+}
+
+/// State one mining worker needs, free of any reference to the block itself.
+struct Search<'a> {
+    prefix: Sha256,
+    target: Sha256Hash,
+    found: &'a AtomicBool,
+    cancel: &'a AtomicBool,
+    hashes: &'a AtomicU64,
+}
+
+impl<'a> Search<'a> {
+    fn run(&self, first: u64, last: u64) -> Option<(u64, Sha256Hash)> {
+        let mut tried = 0;
+        let mut solution = None;
+        for nonce in first..=last {
+            if tried % CANCEL_CHECK_INTERVAL == 0
+                && (self.found.load(Ordering::Relaxed) || self.cancel.load(Ordering::Relaxed))
+            {
+                break;
+            }
+            tried += 1;
+            let hash = calculate_hash(self.prefix, nonce);
+            // Both are big-endian, so byte order matches numeric order
+            if hash <= self.target {
+                self.found.store(true, Ordering::Relaxed);
+                solution = Some((nonce, hash));
+                break;
             }
         }
-        Err(MiningError::Iteration)
+        self.hashes.fetch_add(tried, Ordering::Relaxed);
+        solution
     }
+}
 
-    fn calculate_hash(&self, nonce: u64) -> Sha256Hash {
-        let mut headers = self.block.headers();
-        headers.extend_from_slice(&convert_u64_to_u8_array(nonce));
+fn calculate_hash(mut prefix: Sha256, nonce: u64) -> Sha256Hash {
+    prefix.input(&convert_u64_to_u8_array(nonce));
+    let mut hash = Sha256Hash::default();
+    prefix.result(&mut hash);
+    hash
+}
 
-        let mut hasher = Sha256::new();
-        hasher.input(&headers);
-        let mut hash = Sha256Hash::default();
-        hasher.result(&mut hash);
-        hash
+/// Target that requires `bits` leading zero bits in the block hash.
+pub fn target_from_bits(bits: u64) -> BigUint {
+    let target = 1_u64.to_biguint().unwrap();
+    target << (256 - bits) as usize
+}
+
+/// Expected number of hashes needed to find a block below `target`, used to
+/// compare the cumulative work of competing branches.
+pub fn block_work(target: &BigUint) -> BigUint {
+    let one = 1_u64.to_biguint().unwrap();
+    (&one << 256) / (target + &one)
+}
+
+pub fn target_to_bytes(target: &BigUint) -> Sha256Hash {
+    let bytes = target.to_bytes_be();
+    let mut hash = Sha256Hash::default();
+    hash[32 - bytes.len()..].copy_from_slice(&bytes);
+    hash
+}
+
+/// Scales `prev_target` by how long the last window actually took compared to the
+/// expected timespan. The change is clamped to `max_adjustment` in both directions
+/// and never gets easier than the network's proof of work limit.
+pub fn retarget(prev_target: &BigUint, actual_timespan: u64, params: &ChainParams) -> BigUint {
+    let expected = params.target_timespan().to_biguint().unwrap();
+    let max_adjustment = params.max_adjustment.to_biguint().unwrap();
+    let mut target = prev_target * actual_timespan.to_biguint().unwrap() / expected;
+
+    let lowest = prev_target / &max_adjustment;
+    let highest = prev_target * &max_adjustment;
+    if target < lowest {
+        target = lowest;
+    } else if target > highest {
+        target = highest;
+    }
+
+    let pow_limit = target_from_bits(params.pow_limit_bits);
+    if target > pow_limit {
+        pow_limit
+    } else if target == 0_u64.to_biguint().unwrap() {
+        1_u64.to_biguint().unwrap()
+    } else {
+        target
     }
 }
 
@@ -65,3 +213,76 @@ pub fn convert_u64_to_u8_array(val: u64) -> [u8; 8] {
         (val >> 56) as u8,
     ];
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use num_bigint::BigUint;
+
+    use crate::block::{Block, Sha256Hash};
+    use crate::chain_params::ChainParams;
+    use crate::mining_error::MiningError;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+
+    use super::{retarget, target_from_bits, target_to_bytes, ProofOfWork};
+
+    #[test]
+    fn retarget_is_clamped() {
+        let params = ChainParams::test();
+        let prev = target_from_bits(params.pow_limit_bits + 4);
+        let expected = params.target_timespan();
+
+        assert_eq!(retarget(&prev, expected, &params), prev);
+        assert_eq!(retarget(&prev, expected * 2, &params), &prev * 2_u64);
+        assert_eq!(retarget(&prev, 0, &params), &prev / 4_u64);
+        assert_eq!(retarget(&prev, expected * 100, &params), &prev * 4_u64);
+        assert_eq!(
+            retarget(&target_from_bits(params.pow_limit_bits), expected * 2, &params),
+            target_from_bits(params.pow_limit_bits)
+        );
+    }
+
+    #[test]
+    fn target_bytes_round_trip() {
+        let target = target_from_bits(15);
+        assert_eq!(BigUint::from_bytes_be(&target_to_bytes(&target)), target);
+    }
+
+    #[test]
+    fn mining_can_be_cancelled() {
+        let address = Wallet::new().get_address();
+        let coinbase = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancel);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flag.store(true, Ordering::Relaxed);
+        });
+
+        let unreachable = target_to_bytes(&BigUint::from(1_u64));
+        let prev_hash = Sha256Hash::default();
+        match Block::mine(vec![coinbase], prev_hash, 0, unreachable, 0, 2, &cancel) {
+            Err(MiningError::Cancelled) => (),
+            _ => panic!("mining was not cancelled"),
+        }
+    }
+
+    #[test]
+    fn parallel_mining_finds_valid_block() {
+        let address = Wallet::new().get_address();
+        let coinbase = Transaction::new_coinbase_tx(&address, "".to_string(), 5000).unwrap();
+        let target = target_from_bits(12);
+        let target_bytes = target_to_bytes(&target);
+        let cancel = AtomicBool::new(false);
+        let prev_hash = Sha256Hash::default();
+        let (block, stats) =
+            Block::mine(vec![coinbase], prev_hash, 0, target_bytes, 0, 4, &cancel).unwrap();
+        assert!(ProofOfWork::new(&block).validate(&target));
+        assert!(stats.hashes > 0);
+    }
+}
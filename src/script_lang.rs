@@ -2,13 +2,30 @@ extern crate serde;
 extern crate serde_big_array;
 
 
+use std::convert::TryInto;
+use std::str::FromStr;
 use std::{error, fmt};
 
 
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rustc_serialize::hex::{FromHex, ToHex};
 use secp256k1::{Message, PublicKey, Secp256k1, Signature};
 
 use crate::block::Sha256Hash;
-use crate::wallet::{address_to_pub_hash, hash_pub_key, KeyHash, PubKeyBytes};
+use crate::transaction::{SigHashType, LOCK_TIME_THRESHOLD};
+use crate::wallet::{
+    hash160, hash_pub_key, hash_script, Address, KeyHash, PubKeyBytes, SignatureBytes,
+};
+
+/// Most keys a multisig script may list
+pub const MAX_MULTISIG_KEYS: usize = 20;
+/// Largest serialized size, in bytes, of a script the interpreter runs
+pub const MAX_SCRIPT_SIZE: u64 = 10_000;
+/// Most opcodes, pushes not counted, a script may contain
+pub const MAX_SCRIPT_OPS: usize = 201;
+/// Most values the stack may hold
+pub const MAX_STACK_SIZE: usize = 1_000;
 
 big_array! {
     BigArray;
@@ -16,23 +33,154 @@ big_array! {
     64,
 }
 
+/// Unlocking data of an input. Its values are pushed onto the stack before the
+/// locking script of the spent output runs.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ScriptSig {
-    #[serde(with = "BigArray")]
-    pub signature: [u8; 64],
-    #[serde(with = "BigArray")]
-    pub pub_key: PubKeyBytes,
+    pub values: Vec<StackValues>,
+    /// Script a pay-to-script-hash output commits to, run in place of its own
+    pub redeem_script: Option<ScriptPubKey>,
+    pub sighash: SigHashType,
+}
+
+/// ASM form of the pushed values, the redeem script is left out.
+impl fmt::Display for ScriptSig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words: Vec<String> = self.values.iter().map(|value| value.to_string()).collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+impl ScriptSig {
+    /// Placeholder for an input that is signed later.
+    pub fn unsigned(pub_key: PubKeyBytes) -> Self {
+        Self::pay_to_address([0; 64], pub_key, SigHashType::ALL)
+    }
+
+    /// Unlocks a `pay_to_address_script` output.
+    pub fn pay_to_address(
+        signature: SignatureBytes,
+        pub_key: PubKeyBytes,
+        sighash: SigHashType,
+    ) -> Self {
+        ScriptSig {
+            values: vec![StackValues::Signature(signature), StackValues::PubKey(pub_key)],
+            redeem_script: None,
+            sighash,
+        }
+    }
+
+    /// Unlocks a `multisig_script` output, signatures in the order of their keys.
+    pub fn multisig(signatures: Vec<SignatureBytes>, sighash: SigHashType) -> Self {
+        ScriptSig {
+            values: signatures.into_iter().map(StackValues::Signature).collect(),
+            redeem_script: None,
+            sighash,
+        }
+    }
+
+    /// Unlocks a `pay_to_script_hash_script` output by revealing `redeem_script`,
+    /// which then runs on `values`.
+    pub fn pay_to_script_hash(
+        values: Vec<StackValues>,
+        redeem_script: ScriptPubKey,
+        sighash: SigHashType,
+    ) -> Self {
+        ScriptSig {
+            values,
+            redeem_script: Some(redeem_script),
+            sighash,
+        }
+    }
+
+    /// Claims an `htlc_script` output with the preimage of its hash lock.
+    pub fn htlc_claim(
+        signature: SignatureBytes,
+        pub_key: PubKeyBytes,
+        preimage: &[u8],
+        sighash: SigHashType,
+    ) -> Self {
+        let mut script_sig = Self::pay_to_address(signature, pub_key, sighash);
+        script_sig.values.push(StackValues::Bytes(preimage.to_vec()));
+        script_sig.values.push(StackValues::Value(1));
+        script_sig
+    }
+
+    /// Takes an `htlc_script` output back once its timeout passed.
+    pub fn htlc_refund(
+        signature: SignatureBytes,
+        pub_key: PubKeyBytes,
+        sighash: SigHashType,
+    ) -> Self {
+        let mut script_sig = Self::pay_to_address(signature, pub_key, sighash);
+        script_sig.values.push(StackValues::Value(0));
+        script_sig
+    }
+
+    /// Key revealed by a pay-to-address input.
+    pub fn pub_key(&self) -> Option<&PubKeyBytes> {
+        match self.values.last() {
+            Some(StackValues::PubKey(pub_key)) => Some(pub_key),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Reason a script failed to run. Opcode level variants carry the name of the
+/// offending opcode and its position in the script.
+#[derive(Debug, PartialEq)]
 pub enum ScriptError {
-    WrongValue,
+    ScriptSize(u64),
+    OpCount(usize),
+    ScriptSigSize(usize),
+    MissingRedeemScript,
+    StackUnderflow(&'static str, usize),
+    StackOverflow(&'static str, usize),
+    WrongValue(&'static str, usize),
+    Overflow(&'static str, usize),
+    VerifyFailed(&'static str, usize),
+    MissingHash(&'static str, usize),
+    UnbalancedConditional(usize),
+    CleanStack(usize),
 }
 
 impl fmt::Display for ScriptError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            ScriptError::WrongValue => write!(f, "Wrong value"),
+            ScriptError::ScriptSize(size) => {
+                write!(f, "script is {} bytes, more than the {} allowed", size, MAX_SCRIPT_SIZE)
+            }
+            ScriptError::OpCount(count) => {
+                write!(f, "script has {} opcodes, more than the {} allowed", count, MAX_SCRIPT_OPS)
+            }
+            ScriptError::ScriptSigSize(count) => write!(
+                f,
+                "script sig pushes {} values, more than the {} allowed",
+                count, MAX_STACK_SIZE
+            ),
+            ScriptError::MissingRedeemScript => {
+                write!(f, "pay-to-script-hash input does not reveal a redeem script")
+            }
+            ScriptError::StackUnderflow(op, position) => {
+                write!(f, "{} at {} found too few values on the stack", op, position)
+            }
+            ScriptError::StackOverflow(op, position) => {
+                write!(f, "{} at {} grew the stack past {} values", op, position, MAX_STACK_SIZE)
+            }
+            ScriptError::WrongValue(op, position) => {
+                write!(f, "{} at {} found a value of the wrong type", op, position)
+            }
+            ScriptError::Overflow(op, position) => write!(f, "{} at {} overflowed", op, position),
+            ScriptError::VerifyFailed(op, position) => write!(f, "{} at {} failed", op, position),
+            ScriptError::MissingHash(op, position) => {
+                write!(f, "{} at {} needs the spending transaction hash", op, position)
+            }
+            ScriptError::UnbalancedConditional(position) => {
+                write!(f, "unbalanced OP_IF, OP_ELSE or OP_ENDIF at {}", position)
+            }
+            ScriptError::CleanStack(count) => {
+                write!(f, "script left {} values on the stack instead of one", count)
+            }
         }
     }
 }
@@ -40,7 +188,18 @@ impl fmt::Display for ScriptError {
 impl error::Error for ScriptError {
     fn description(&self) -> &str {
         match *self {
-            ScriptError::WrongValue => "Wrong value",
+            ScriptError::ScriptSize(_) => "Script too large",
+            ScriptError::OpCount(_) => "Too many opcodes",
+            ScriptError::ScriptSigSize(_) => "Script sig too large",
+            ScriptError::MissingRedeemScript => "Missing redeem script",
+            ScriptError::StackUnderflow(..) => "Stack underflow",
+            ScriptError::StackOverflow(..) => "Stack overflow",
+            ScriptError::WrongValue(..) => "Wrong value",
+            ScriptError::Overflow(..) => "Arithmetic overflow",
+            ScriptError::VerifyFailed(..) => "Verify failed",
+            ScriptError::MissingHash(..) => "Missing transaction hash",
+            ScriptError::UnbalancedConditional(_) => "Unbalanced conditional",
+            ScriptError::CleanStack(_) => "Unclean stack",
         }
     }
 
@@ -49,7 +208,7 @@ impl error::Error for ScriptError {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum StackValues {
     Value(u32),
     #[serde(with = "BigArray")]
@@ -57,132 +216,553 @@ pub enum StackValues {
     PubKeyHash(KeyHash),
     #[serde(with = "BigArray")]
     PubKey(PubKeyBytes),
+    ScriptHash(KeyHash),
+    Bytes(Vec<u8>),
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+impl StackValues {
+    /// Raw bytes of the value, what the hash opcodes hash.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            StackValues::Value(value) => value.to_le_bytes().to_vec(),
+            StackValues::Signature(signature) => signature.to_vec(),
+            StackValues::PubKeyHash(hash) | StackValues::ScriptHash(hash) => hash.to_vec(),
+            StackValues::PubKey(pub_key) => pub_key.to_vec(),
+            StackValues::Bytes(bytes) => bytes.clone(),
+        }
+    }
+
+    /// Condition `OpIf` branches on, and the result of a script: zero and empty or
+    /// all-zero bytes are false.
+    fn is_true(&self) -> bool {
+        match self {
+            StackValues::Value(value) => *value != 0,
+            StackValues::Bytes(bytes) => bytes.iter().any(|byte| *byte != 0),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum ScriptToken {
     OpAdd,
     OpEqual,
     OpEqualVerify,
     OpCheckSig,
+    OpCheckMultiSig,
+    OpCheckLockTimeVerify,
+    OpCheckSequenceVerify,
     OpHash160,
+    OpSha256,
     OpDup,
+    OpIf,
+    OpElse,
+    OpEndIf,
     Value(StackValues),
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+impl From<bool> for StackValues {
+    fn from(value: bool) -> Self {
+        StackValues::Value(value as u32)
+    }
+}
+
+impl ScriptToken {
+    /// Opcode name used in ASM and errors, pushes are `PUSH`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptToken::OpAdd => "OP_ADD",
+            ScriptToken::OpEqual => "OP_EQUAL",
+            ScriptToken::OpEqualVerify => "OP_EQUALVERIFY",
+            ScriptToken::OpCheckSig => "OP_CHECKSIG",
+            ScriptToken::OpCheckMultiSig => "OP_CHECKMULTISIG",
+            ScriptToken::OpCheckLockTimeVerify => "OP_CHECKLOCKTIMEVERIFY",
+            ScriptToken::OpCheckSequenceVerify => "OP_CHECKSEQUENCEVERIFY",
+            ScriptToken::OpHash160 => "OP_HASH160",
+            ScriptToken::OpSha256 => "OP_SHA256",
+            ScriptToken::OpDup => "OP_DUP",
+            ScriptToken::OpIf => "OP_IF",
+            ScriptToken::OpElse => "OP_ELSE",
+            ScriptToken::OpEndIf => "OP_ENDIF",
+            ScriptToken::Value(_) => "PUSH",
+        }
+    }
+}
+
+/// Fields of the spending transaction a script can check besides signatures.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpendContext {
+    pub lock_time: u64,
+    /// Sequence of the input running the script
+    pub sequence: u32,
+}
+
+/// Every opcode, in the order `ScriptToken` declares them
+pub const OPCODES: [ScriptToken; 13] = [
+    ScriptToken::OpAdd,
+    ScriptToken::OpEqual,
+    ScriptToken::OpEqualVerify,
+    ScriptToken::OpCheckSig,
+    ScriptToken::OpCheckMultiSig,
+    ScriptToken::OpCheckLockTimeVerify,
+    ScriptToken::OpCheckSequenceVerify,
+    ScriptToken::OpHash160,
+    ScriptToken::OpSha256,
+    ScriptToken::OpDup,
+    ScriptToken::OpIf,
+    ScriptToken::OpElse,
+    ScriptToken::OpEndIf,
+];
+
+/// ASM form of a pushed value. Numbers are written in decimal, key hashes, keys
+/// and signatures as `<hex>`. Script hashes, and byte strings as long as one of
+/// those, are tagged: `<script:hex>` and `<bytes:hex>`.
+impl fmt::Display for StackValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackValues::Value(value) => write!(f, "{}", value),
+            StackValues::ScriptHash(hash) => write!(f, "<script:{}>", hash.to_hex()),
+            StackValues::Bytes(bytes) if matches!(bytes.len(), 20 | 33 | 64) => {
+                write!(f, "<bytes:{}>", bytes.to_hex())
+            }
+            value => write!(f, "<{}>", value.to_bytes().to_hex()),
+        }
+    }
+}
+
+impl FromStr for StackValues {
+    type Err = ParseScriptError;
+
+    fn from_str(word: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseScriptError::InvalidPush(word.to_string());
+        if let Ok(value) = word.parse::<u32>() {
+            return Ok(StackValues::Value(value));
+        }
+        let data = word
+            .strip_prefix('<')
+            .and_then(|data| data.strip_suffix('>'))
+            .ok_or_else(invalid)?;
+        let (tag, hex) = match data.split_once(':') {
+            Some((tag, hex)) => (Some(tag), hex),
+            None => (None, data),
+        };
+        let bytes = hex.from_hex().map_err(|_| invalid())?;
+        match (tag, bytes.len()) {
+            (Some("bytes"), _) => Ok(StackValues::Bytes(bytes)),
+            (Some("script"), 20) => Ok(StackValues::ScriptHash(bytes[..].try_into().unwrap())),
+            (None, 20) => Ok(StackValues::PubKeyHash(bytes[..].try_into().unwrap())),
+            (None, 33) => Ok(StackValues::PubKey(bytes[..].try_into().unwrap())),
+            (None, 64) => Ok(StackValues::Signature(bytes[..].try_into().unwrap())),
+            (None, _) => Ok(StackValues::Bytes(bytes)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ScriptToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptToken::Value(value) => write!(f, "{}", value),
+            opcode => write!(f, "{}", opcode.name()),
+        }
+    }
+}
+
+impl FromStr for ScriptToken {
+    type Err = ParseScriptError;
+
+    fn from_str(word: &str) -> Result<Self, Self::Err> {
+        if !word.starts_with("OP_") {
+            return word.parse().map(ScriptToken::Value);
+        }
+        OPCODES
+            .iter()
+            .find(|opcode| opcode.name() == word)
+            .cloned()
+            .ok_or_else(|| ParseScriptError::UnknownOpcode(word.to_string()))
+    }
+}
+
+/// Text that is not a valid ASM script.
+#[derive(Debug, PartialEq)]
+pub enum ParseScriptError {
+    UnknownOpcode(String),
+    InvalidPush(String),
+}
+
+impl fmt::Display for ParseScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseScriptError::UnknownOpcode(ref word) => write!(f, "unknown opcode {}", word),
+            ParseScriptError::InvalidPush(ref word) => write!(f, "invalid push {}", word),
+        }
+    }
+}
+
+impl error::Error for ParseScriptError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseScriptError::UnknownOpcode(_) => "Unknown opcode",
+            ParseScriptError::InvalidPush(_) => "Invalid push",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct ScriptPubKey {
     pub script: Vec<ScriptToken>,
 }
 
+/// ASM form of the script, `OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG`
+/// for a pay-to-address one.
+impl fmt::Display for ScriptPubKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words: Vec<String> = self.script.iter().map(|token| token.to_string()).collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+impl FromStr for ScriptPubKey {
+    type Err = ParseScriptError;
+
+    fn from_str(asm: &str) -> Result<Self, Self::Err> {
+        let script = asm.split_whitespace().map(str::parse).collect::<Result<_, _>>()?;
+        Ok(ScriptPubKey { script })
+    }
+}
+
 impl ScriptPubKey {
     pub fn new() -> ScriptPubKey {
         ScriptPubKey { script: vec![] }
     }
-    fn add(&mut self, value: ScriptToken) {
-        self.script.push(value)
+    /// Redeem script hash of a `pay_to_script_hash_script`.
+    pub fn script_hash(&self) -> Option<KeyHash> {
+        match self.script[..] {
+            [
+                ScriptToken::OpHash160,
+                ScriptToken::Value(StackValues::ScriptHash(hash)),
+                ScriptToken::OpEqual,
+            ] => Some(hash),
+            _ => None,
+        }
     }
+    /// Required signature count and keys of a `multisig_script`.
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<PubKeyBytes>)> {
+        let (last, rest) = self.script.split_last()?;
+        let (count, rest) = rest.split_last()?;
+        let (required, keys) = rest.split_first()?;
+        let pub_keys: Vec<PubKeyBytes> = keys
+            .iter()
+            .map(|token| match token {
+                ScriptToken::Value(StackValues::PubKey(pub_key)) => Some(*pub_key),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        match (required, count, last) {
+            (
+                ScriptToken::Value(StackValues::Value(required)),
+                ScriptToken::Value(StackValues::Value(count)),
+                ScriptToken::OpCheckMultiSig,
+            ) if *count as usize == pub_keys.len() && *required <= *count => {
+                Some((*required as usize, pub_keys))
+            }
+            _ => None,
+        }
+    }
+    /// Runs the script on the values of `script_sig`. `Ok(false)` if it ends with
+    /// a false value, an error if any opcode fails or a limit is exceeded.
     pub fn verify(
         &self,
         script_sig: Option<&ScriptSig>,
         tx_in_hash: Option<&Sha256Hash>,
+    ) -> Result<bool, ScriptError> {
+        self.verify_with_context(script_sig, tx_in_hash, &SpendContext::default())
+    }
+    pub fn verify_with_context(
+        &self,
+        script_sig: Option<&ScriptSig>,
+        tx_in_hash: Option<&Sha256Hash>,
+        context: &SpendContext,
     ) -> Result<bool, ScriptError> {
         use ScriptToken::*;
-        let mut stack: Vec<StackValues>;
-        if let Some(sig) = script_sig {
-            stack = vec![
-                StackValues::Signature(sig.signature.clone()),
-                StackValues::PubKey(sig.pub_key.clone()),
-            ];
-        } else {
-            stack = vec![]
-        }
-
-        let mut return_result = Err(ScriptError::WrongValue);
-        for token in &self.script {
+        let size = bincode::serialized_size(self).unwrap();
+        if size > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::ScriptSize(size));
+        }
+        let op_count = self.script.iter().filter(|token| !matches!(token, Value(_))).count();
+        if op_count > MAX_SCRIPT_OPS {
+            return Err(ScriptError::OpCount(op_count));
+        }
+        if let Some(expected) = self.script_hash() {
+            let redeem_script = script_sig
+                .and_then(|sig| sig.redeem_script.as_ref())
+                .ok_or(ScriptError::MissingRedeemScript)?;
+            if hash_script(redeem_script) != expected || redeem_script.script_hash().is_some() {
+                return Ok(false);
+            }
+            return redeem_script.verify_with_context(script_sig, tx_in_hash, context);
+        }
+        let mut stack: Vec<StackValues> = match script_sig {
+            Some(sig) => sig.values.clone(),
+            None => vec![],
+        };
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::ScriptSigSize(stack.len()));
+        }
+
+        // Whether each enclosing `OpIf` branch runs, tokens only run if all of them do
+        let mut branches: Vec<bool> = vec![];
+        for (position, token) in self.script.iter().enumerate() {
+            let fail =
+                |error: fn(&'static str, usize) -> ScriptError| error(token.name(), position);
+            let executing = branches.iter().all(|taken| *taken);
             match token {
+                OpIf => {
+                    let taken = executing && pop(&mut stack, token, position)?.is_true();
+                    branches.push(taken);
+                }
+                OpElse => match branches.last_mut() {
+                    Some(taken) => *taken = !*taken,
+                    None => return Err(ScriptError::UnbalancedConditional(position)),
+                },
+                OpEndIf => {
+                    if branches.pop().is_none() {
+                        return Err(ScriptError::UnbalancedConditional(position));
+                    }
+                }
+                _ if !executing => {}
                 Value(data) => stack.push(data.clone()),
                 OpDup => {
-                    if let Some(value) = stack.last() {
-                        stack.push(value.clone())
-                    } else {
-                        return_result = Err(ScriptError::WrongValue);
-                    }
+                    let value = stack.last().cloned();
+                    stack.push(value.ok_or_else(|| fail(ScriptError::StackUnderflow))?);
                 }
-                OpHash160 => {
-                    let value = stack.pop();
-                    if let Some(StackValues::PubKey(data)) = value {
-                        stack.push(StackValues::PubKeyHash(hash_pub_key(&data)));
-                    } else {
-                        return_result = Err(ScriptError::WrongValue);
+                OpHash160 => match pop(&mut stack, token, position)? {
+                    StackValues::PubKey(data) => {
+                        stack.push(StackValues::PubKeyHash(hash_pub_key(&data)))
                     }
+                    value => stack.push(StackValues::Bytes(hash160(&value.to_bytes()).to_vec())),
+                },
+                OpSha256 => {
+                    let value = pop(&mut stack, token, position)?;
+                    stack.push(StackValues::Bytes(sha256(&value.to_bytes()).to_vec()));
                 }
                 OpAdd => {
-                    let first_value = stack.pop();
-                    let second_value = stack.pop();
-                    if let (Some(StackValues::Value(value1)), Some(StackValues::Value(value2))) =
-                    (first_value, second_value)
-                    {
-                        let result = value1 + value2;
-                        stack.push(StackValues::Value(result));
-                    } else {
-                        return_result = Err(ScriptError::WrongValue);
-                    }
+                    let value1 = pop_number(&mut stack, token, position)?;
+                    let value2 = pop_number(&mut stack, token, position)?;
+                    let result = value1
+                        .checked_add(value2)
+                        .ok_or_else(|| fail(ScriptError::Overflow))?;
+                    stack.push(StackValues::Value(result));
                 }
+                // Compared as bytes, a pushed hash equals the same hash computed here
                 OpEqual => {
-                    let first_value = stack.pop();
-                    let second_value = stack.pop();
-                    if let (Some(StackValues::Value(value1)), Some(StackValues::Value(value2))) =
-                    (first_value, second_value)
-                    {
-                        return_result = Ok(value1 == value2);
-                    } else {
-                        return_result = Err(ScriptError::WrongValue);
-                    }
+                    let value1 = pop(&mut stack, token, position)?;
+                    let value2 = pop(&mut stack, token, position)?;
+                    stack.push(StackValues::from(value1.to_bytes() == value2.to_bytes()));
                 }
                 OpEqualVerify => {
-                    let first_value = stack.pop();
-                    let second_value = stack.pop();
-                    if let (
-                        Some(StackValues::PubKeyHash(value1)),
-                        Some(StackValues::PubKeyHash(value2)),
-                    ) = (first_value, second_value)
-                    {
-                        return_result = Ok(value1 == value2);
-                        println!("{:?}", return_result)
-                    } else {
-                        return_result = Err(ScriptError::WrongValue);
+                    let value1 = pop(&mut stack, token, position)?;
+                    let value2 = pop(&mut stack, token, position)?;
+                    if value1.to_bytes() != value2.to_bytes() {
+                        return Err(fail(ScriptError::VerifyFailed));
                     }
                 }
                 OpCheckSig => {
-                    let first_value = stack.pop();
-                    let second_value = stack.pop();
-                    if let (
-                        Some(StackValues::PubKey(pub_key)),
-                        Some(StackValues::Signature(sign)),
-                    ) = (first_value, second_value)
-                    {
-                        if let Some(tx_hash) = tx_in_hash {
-                            return_result = Ok(verify(tx_hash, &pub_key, &sign))
-                        } else {
-                            return_result = Err(ScriptError::WrongValue);
-                        }
-                    } else {
-                        return_result = Err(ScriptError::WrongValue);
+                    let pub_key = match pop(&mut stack, token, position)? {
+                        StackValues::PubKey(pub_key) => pub_key,
+                        _ => return Err(fail(ScriptError::WrongValue)),
+                    };
+                    let signature = match pop(&mut stack, token, position)? {
+                        StackValues::Signature(signature) => signature,
+                        _ => return Err(fail(ScriptError::WrongValue)),
+                    };
+                    let tx_hash = tx_in_hash.ok_or_else(|| fail(ScriptError::MissingHash))?;
+                    stack.push(StackValues::from(verify(tx_hash, &pub_key, &signature)));
+                }
+                OpCheckMultiSig => {
+                    let valid = check_multisig(&mut stack, tx_in_hash, position)?;
+                    stack.push(StackValues::from(valid));
+                }
+                // Unlike Bitcoin the lock time is popped, there is no OP_DROP
+                OpCheckLockTimeVerify => {
+                    let required = pop_number(&mut stack, token, position)?;
+                    if !lock_time_reached(context.lock_time, required as u64) {
+                        return Err(fail(ScriptError::VerifyFailed));
                     }
                 }
+                // Block validation makes sure the output is as old as the sequence says
+                OpCheckSequenceVerify => {
+                    if context.sequence < pop_number(&mut stack, token, position)? {
+                        return Err(fail(ScriptError::VerifyFailed));
+                    }
+                }
+            }
+            if stack.len() > MAX_STACK_SIZE {
+                return Err(fail(ScriptError::StackOverflow));
             }
         }
-        if stack.is_empty() {
-            return return_result;
-        } else {
-            return Err(ScriptError::WrongValue);
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional(self.script.len()));
+        }
+        match stack[..] {
+            [ref result] => Ok(result.is_true()),
+            _ => Err(ScriptError::CleanStack(stack.len())),
         }
     }
 }
 
-pub fn pay_to_address_script(address: &String) -> ScriptPubKey {
-    let pub_key_hash = address_to_pub_hash(address);
+/// Pops the operand of `token`, the opcode at `position`.
+fn pop(
+    stack: &mut Vec<StackValues>,
+    token: &ScriptToken,
+    position: usize,
+) -> Result<StackValues, ScriptError> {
+    stack
+        .pop()
+        .ok_or_else(|| ScriptError::StackUnderflow(token.name(), position))
+}
+
+/// `pop` for operands that have to be numbers.
+fn pop_number(
+    stack: &mut Vec<StackValues>,
+    token: &ScriptToken,
+    position: usize,
+) -> Result<u32, ScriptError> {
+    match pop(stack, token, position)? {
+        StackValues::Value(value) => Ok(value),
+        _ => Err(ScriptError::WrongValue(token.name(), position)),
+    }
+}
+
+/// Pops `N`, the keys, `M` and `M` signatures, and checks that every signature
+/// belongs to a different key, in the order the keys are listed.
+fn check_multisig(
+    stack: &mut Vec<StackValues>,
+    tx_in_hash: Option<&Sha256Hash>,
+    position: usize,
+) -> Result<bool, ScriptError> {
+    let token = &ScriptToken::OpCheckMultiSig;
+    let wrong_value = || ScriptError::WrongValue(token.name(), position);
+    let key_count = pop_number(stack, token, position)? as usize;
+    if key_count > MAX_MULTISIG_KEYS {
+        return Err(wrong_value());
+    }
+    let mut pub_keys = vec![];
+    for _ in 0..key_count {
+        match pop(stack, token, position)? {
+            StackValues::PubKey(pub_key) => pub_keys.push(pub_key),
+            _ => return Err(wrong_value()),
+        }
+    }
+    pub_keys.reverse();
+    let required = pop_number(stack, token, position)? as usize;
+    if required > key_count {
+        return Err(wrong_value());
+    }
+    let mut signatures = vec![];
+    for _ in 0..required {
+        match pop(stack, token, position)? {
+            StackValues::Signature(signature) => signatures.push(signature),
+            _ => return Err(wrong_value()),
+        }
+    }
+    signatures.reverse();
+    let tx_hash =
+        tx_in_hash.ok_or_else(|| ScriptError::MissingHash(token.name(), position))?;
+
+    let mut keys = pub_keys.iter();
+    for signature in &signatures {
+        if !keys.any(|pub_key| verify(tx_hash, pub_key, signature)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn sha256(data: &[u8]) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut hash = Sha256Hash::default();
+    hasher.result(&mut hash);
+    hash
+}
+
+/// Whether a transaction lock time satisfies a script requiring `required`. Both
+/// have to be heights or both timestamps.
+fn lock_time_reached(lock_time: u64, required: u64) -> bool {
+    (lock_time < LOCK_TIME_THRESHOLD) == (required < LOCK_TIME_THRESHOLD) && lock_time >= required
+}
+
+/// `pay_to_address_script` that only a transaction with a lock time of at least
+/// `lock_time` can spend.
+pub fn lock_time_script(lock_time: u32, address: &Address) -> ScriptPubKey {
+    let mut script = vec![
+        ScriptToken::Value(StackValues::Value(lock_time)),
+        ScriptToken::OpCheckLockTimeVerify,
+    ];
+    script.extend(pay_to_address_script(address).script);
+    ScriptPubKey { script }
+}
+
+/// `pay_to_address_script` that an input can only spend once the output is
+/// `blocks` blocks deep, by setting its sequence to at least `blocks`.
+pub fn sequence_lock_script(blocks: u32, address: &Address) -> ScriptPubKey {
+    let mut script = vec![
+        ScriptToken::Value(StackValues::Value(blocks)),
+        ScriptToken::OpCheckSequenceVerify,
+    ];
+    script.extend(pay_to_address_script(address).script);
+    ScriptPubKey { script }
+}
+
+/// Hash-time-locked contract: `recipient` can claim the output by revealing data
+/// whose SHA-256 is `hash_lock`, `sender` can take it back with a lock time of at
+/// least `timeout`. Both are key hashes, the branches pay to addresses.
+pub fn htlc_script(
+    hash_lock: &Sha256Hash,
+    recipient: &KeyHash,
+    sender: &KeyHash,
+    timeout: u32,
+) -> ScriptPubKey {
+    let pay_to = |pub_key_hash: &KeyHash| {
+        pay_to_address_script(&Address::PubKeyHash(*pub_key_hash)).script
+    };
+    let mut script = vec![
+        ScriptToken::OpIf,
+        ScriptToken::OpSha256,
+        ScriptToken::Value(StackValues::Bytes(hash_lock.to_vec())),
+        ScriptToken::OpEqualVerify,
+    ];
+    script.extend(pay_to(recipient));
+    script.push(ScriptToken::OpElse);
+    script.push(ScriptToken::Value(StackValues::Value(timeout)));
+    script.push(ScriptToken::OpCheckLockTimeVerify);
+    script.extend(pay_to(sender));
+    script.push(ScriptToken::OpEndIf);
+    ScriptPubKey { script }
+}
+
+/// Locking script spendable with signatures of `required` of the `pub_keys`.
+pub fn multisig_script(required: u32, pub_keys: &[PubKeyBytes]) -> ScriptPubKey {
+    let mut script = vec![ScriptToken::Value(StackValues::Value(required))];
+    for pub_key in pub_keys {
+        script.push(ScriptToken::Value(StackValues::PubKey(*pub_key)));
+    }
+    script.push(ScriptToken::Value(StackValues::Value(pub_keys.len() as u32)));
+    script.push(ScriptToken::OpCheckMultiSig);
+    ScriptPubKey { script }
+}
+
+/// Locking script for `address`, pay-to-script-hash for script addresses.
+pub fn pay_to_address_script(address: &Address) -> ScriptPubKey {
+    let pub_key_hash = match *address {
+        Address::PubKeyHash(pub_key_hash) => pub_key_hash,
+        Address::ScriptHash(script_hash) => return script_hash_script(script_hash),
+    };
     ScriptPubKey {
         script: vec![
             ScriptToken::OpDup,
@@ -194,15 +774,30 @@ pub fn pay_to_address_script(address: &String) -> ScriptPubKey {
     }
 }
 
+/// Locking script spendable by revealing `redeem_script` and satisfying it.
+pub fn pay_to_script_hash_script(redeem_script: &ScriptPubKey) -> ScriptPubKey {
+    script_hash_script(hash_script(redeem_script))
+}
+
+fn script_hash_script(script_hash: KeyHash) -> ScriptPubKey {
+    ScriptPubKey {
+        script: vec![
+            ScriptToken::OpHash160,
+            ScriptToken::Value(StackValues::ScriptHash(script_hash)),
+            ScriptToken::OpEqual,
+        ],
+    }
+}
+
+/// Malformed keys and signatures fail to verify rather than panic.
 fn verify(msg: &Sha256Hash, key: &PubKeyBytes, signature: &[u8; 64]) -> bool {
     let verificator = Secp256k1::verification_only();
-    verificator
-        .verify(
-            &Message::from_slice(msg).unwrap(),
-            &Signature::from_compact(signature).unwrap(),
-            &PublicKey::from_slice(key).unwrap(),
-        )
-        .is_ok()
+    match (Signature::from_compact(signature), PublicKey::from_slice(key)) {
+        (Ok(signature), Ok(key)) => verificator
+            .verify(&Message::from_slice(msg).unwrap(), &signature, &key)
+            .is_ok(),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
@@ -216,16 +811,26 @@ mod tests {
     use crate::script_lang::{pay_to_address_script, ScriptToken, StackValues};
     use crate::wallet::Wallet;
 
-    use super::{ScriptPubKey, ScriptSig};
+    use rustc_serialize::hex::ToHex;
+
+    use crate::wallet::{hash_pub_key, Address};
+
+    use super::{
+        ParseScriptError, ScriptError, ScriptPubKey, ScriptSig, MAX_SCRIPT_OPS, MAX_STACK_SIZE,
+        OPCODES,
+    };
 
     #[test]
     fn check_add() {
-        let mut script = ScriptPubKey::new();
-        script.add(Value(StackValues::Value(1)));
-        script.add(Value(StackValues::Value(2)));
-        script.add(OpAdd);
-        script.add(Value(StackValues::Value(3)));
-        script.add(OpEqual);
+        let script = ScriptPubKey {
+            script: vec![
+                Value(StackValues::Value(1)),
+                Value(StackValues::Value(2)),
+                OpAdd,
+                Value(StackValues::Value(3)),
+                OpEqual,
+            ],
+        };
         assert_eq!(script.verify(None, None).unwrap(), true)
     }
 
@@ -233,14 +838,144 @@ mod tests {
     fn verify() {
         
         let wallet = Wallet::new();
-        let script = pay_to_address_script(&wallet.get_address());
+        let address = wallet.get_address().parse().unwrap();
+        let script = pay_to_address_script(&address);
         let data = [1; 32];
-        let script_sig = ScriptSig {
-            pub_key: wallet.public_key,
-            signature: wallet.sign(data.to_vec()),
-        };
+        let signature = wallet.sign(data.to_vec());
+        let script_sig =
+            ScriptSig::pay_to_address(signature, wallet.public_key, Default::default());
         if let Ok(result) = script.verify(Some(&script_sig), Some(&data)) {
             assert_eq!(result, true)
         } else { panic!("fuck") }
     }
+
+    #[test]
+    fn branches_and_hashes() {
+        let branch = |condition: u32| ScriptPubKey {
+            script: vec![
+                Value(StackValues::Value(condition)),
+                OpIf,
+                Value(StackValues::Bytes(b"secret".to_vec())),
+                OpSha256,
+                OpElse,
+                Value(StackValues::Value(7)),
+                OpHash160,
+                OpEndIf,
+                Value(StackValues::Bytes(super::sha256(b"secret").to_vec())),
+                OpEqual,
+            ],
+        };
+        assert_eq!(branch(1).verify(None, None).unwrap(), true);
+        assert_eq!(branch(0).verify(None, None).unwrap(), false);
+
+        let hash_lock = |secret: &[u8]| {
+            let asm = format!(
+                "<{}> OP_HASH160 <{}> OP_EQUAL",
+                secret.to_hex(),
+                super::hash160(b"secret").to_hex()
+            );
+            asm.parse::<ScriptPubKey>().unwrap().verify(None, None).unwrap()
+        };
+        assert_eq!(hash_lock(b"secret"), true);
+        assert_eq!(hash_lock(b"guess"), false);
+
+        let unbalanced = ScriptPubKey {
+            script: vec![Value(StackValues::Value(1)), OpIf, Value(StackValues::Value(1))],
+        };
+        assert!(unbalanced.verify(None, None).is_err());
+        let stray_else = ScriptPubKey {
+            script: vec![OpElse],
+        };
+        assert!(stray_else.verify(None, None).is_err());
+    }
+
+    #[test]
+    fn errors_and_limits() {
+        let run = |script: Vec<ScriptToken>, values: Vec<StackValues>| {
+            let script_sig = ScriptSig {
+                values,
+                redeem_script: None,
+                sighash: Default::default(),
+            };
+            ScriptPubKey { script }.verify(Some(&script_sig), None)
+        };
+        // A failure can't be undone by the opcodes after it
+        let one = Value(StackValues::Value(1));
+        let recovers = vec![OpHash160, one.clone(), one.clone(), OpEqual];
+        assert_eq!(run(recovers, vec![]), Err(ScriptError::StackUnderflow("OP_HASH160", 0)));
+        let mismatch = vec![
+            Value(StackValues::Value(1)),
+            Value(StackValues::Value(2)),
+            OpEqualVerify,
+            Value(StackValues::Value(1)),
+        ];
+        assert_eq!(run(mismatch, vec![]), Err(ScriptError::VerifyFailed("OP_EQUALVERIFY", 2)));
+        let overflow = vec![Value(StackValues::Value(1)), OpAdd];
+        assert_eq!(
+            run(overflow, vec![StackValues::Value(u32::MAX)]),
+            Err(ScriptError::Overflow("OP_ADD", 1))
+        );
+        let leftover = vec![Value(StackValues::Value(1))];
+        assert_eq!(run(leftover, vec![StackValues::Value(1)]), Err(ScriptError::CleanStack(2)));
+
+        assert_eq!(run(vec![OpDup; MAX_SCRIPT_OPS + 1], vec![]), Err(ScriptError::OpCount(202)));
+        let pushes = vec![Value(StackValues::Bytes(vec![0; 100])); 100];
+        assert!(matches!(run(pushes, vec![]), Err(ScriptError::ScriptSize(_))));
+        let full_stack = vec![StackValues::Value(1); MAX_STACK_SIZE];
+        let overflow = run(vec![OpDup], full_stack.clone());
+        assert_eq!(overflow, Err(ScriptError::StackOverflow("OP_DUP", 0)));
+        let mut too_many = full_stack;
+        too_many.push(StackValues::Value(1));
+        assert_eq!(run(vec![], too_many), Err(ScriptError::ScriptSigSize(MAX_STACK_SIZE + 1)));
+    }
+
+    #[test]
+    fn asm_round_trip() {
+        let wallet = Wallet::new();
+        let pub_key_hash = hash_pub_key(&wallet.public_key);
+        let address = Address::PubKeyHash(pub_key_hash);
+        assert_eq!(
+            pay_to_address_script(&address).to_string(),
+            format!("OP_DUP OP_HASH160 <{}> OP_EQUALVERIFY OP_CHECKSIG", pub_key_hash.to_hex())
+        );
+
+        let pushes = vec![
+            StackValues::Value(0),
+            StackValues::Value(u32::MAX),
+            StackValues::Signature([7; 64]),
+            StackValues::PubKeyHash([1; 20]),
+            StackValues::PubKey(wallet.public_key),
+            StackValues::ScriptHash([1; 20]),
+            StackValues::Bytes(vec![]),
+            StackValues::Bytes(vec![3; 32]),
+            StackValues::Bytes(vec![2; 20]),
+            StackValues::Bytes(vec![4; 33]),
+            StackValues::Bytes(vec![5; 64]),
+        ];
+        let tokens: Vec<ScriptToken> =
+            OPCODES.iter().cloned().chain(pushes.into_iter().map(Value)).collect();
+        for token in &tokens {
+            assert_eq!(token.to_string().parse::<ScriptToken>().as_ref(), Ok(token));
+        }
+        // Pseudo random scripts, a linear congruential generator keeps failures reproducible
+        let mut seed: u64 = 1;
+        let mut next = |bound: usize| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 33) as usize % bound
+        };
+        for _ in 0..100 {
+            let length = next(20);
+            let script = ScriptPubKey {
+                script: (0..length).map(|_| tokens[next(tokens.len())].clone()).collect(),
+            };
+            assert_eq!(script.to_string().parse::<ScriptPubKey>(), Ok(script));
+        }
+
+        let parse = |asm: &str| asm.parse::<ScriptPubKey>();
+        assert_eq!(parse("  "), Ok(ScriptPubKey::new()));
+        assert_eq!(parse("1 OP_NOP"), Err(ParseScriptError::UnknownOpcode("OP_NOP".to_string())));
+        for push in &["-1", "<zz>", "<0a", "<script:00>", "<key:00>"] {
+            assert_eq!(parse(push), Err(ParseScriptError::InvalidPush(push.to_string())));
+        }
+    }
 }
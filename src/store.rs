@@ -4,6 +4,9 @@ use std::sync::{Arc, RwLock, RwLockReadGuard};
 
 use rkv::{Manager, Rkv, SingleStore, StoreOptions};
 
+/// Number of named stores a database can hold, one per bucket.
+const MAX_STORES: u32 = 16;
+
 #[derive(Clone)]
 pub(crate) struct Store {
     created_arc: Arc<RwLock<Rkv>>,
@@ -20,7 +23,7 @@ impl Store {
             created_arc: Manager::singleton()
                 .write()
                 .unwrap()
-                .get_or_create(path, Rkv::new)
+                .get_or_create(path, |path| Rkv::with_capacity(path, MAX_STORES))
                 .unwrap(),
         }
     }
@@ -0,0 +1,66 @@
+//! Fixtures shared by the unit tests. Chains and wallets live in a temporary
+//! directory, removed when the returned `TempDir` is dropped.
+
+use std::collections::HashMap;
+
+use rustc_serialize::hex::ToHex;
+use tempfile::{tempdir, TempDir};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::chain_params::ChainParams;
+use crate::proof_of_work::target_to_bytes;
+use crate::transaction::{Transaction, TXInput, TXOutput};
+use crate::wallet::{Wallet, Wallets};
+
+pub(crate) fn test_wallets() -> (TempDir, Wallets) {
+    let dir = tempdir().unwrap();
+    let wallets = Wallets::new(dir.path().join("wallets").to_str().unwrap().to_string());
+    (dir, wallets)
+}
+
+/// `test_chain_with` the test parameters.
+pub(crate) fn test_chain() -> (TempDir, Wallets, Blockchain, String) {
+    test_chain_with(ChainParams::test())
+}
+
+/// Fresh chain and the address of the wallet its genesis block pays.
+pub(crate) fn test_chain_with(params: ChainParams) -> (TempDir, Wallets, Blockchain, String) {
+    let (dir, mut wallets) = test_wallets();
+    let path = dir.path().join("block").to_str().unwrap().to_string();
+    let (bc, address) = Blockchain::with_params(path, &mut wallets, params).unwrap();
+    (dir, wallets, bc, address.unwrap())
+}
+
+/// Block on top of `parent`, which need not be the tip, with the expected target
+/// and the earliest timestamp allowed.
+pub(crate) fn block_on(bc: &Blockchain, parent: &Block, transactions: Vec<Transaction>) -> Block {
+    let target = target_to_bytes(&bc.expected_target(&parent.hash, parent.height + 1));
+    let timestamp = bc.median_time_past(&parent.hash) + 1;
+    Block::new(transactions, parent.hash, parent.height + 1, target, timestamp, 2).unwrap()
+}
+
+/// Unsigned transaction spending output `vout` of `prev_tx` with the key of `from`.
+pub(crate) fn unsigned_spend(
+    prev_tx: &Transaction,
+    vout: i64,
+    from: &Wallet,
+    outputs: Vec<TXOutput>,
+) -> Transaction {
+    Transaction::new(vec![TXInput::new(prev_tx, vout, from)], outputs)
+}
+
+/// Unsigned transaction paying `outputs` out of a zero value coinbase of `from`,
+/// for tests that only verify spends of its outputs.
+pub(crate) fn funding_tx(from: &Wallet, outputs: Vec<TXOutput>) -> Transaction {
+    let coinbase = Transaction::new_coinbase_tx(&from.get_address(), "".to_string(), 0).unwrap();
+    unsigned_spend(&coinbase, 0, from, outputs)
+}
+
+/// Signed transaction sending the first output of `prev_tx`, owned by `from`, to `to`.
+pub(crate) fn spend(prev_tx: &Transaction, from: &Wallet, to: &str, value: u64) -> Transaction {
+    let tx = unsigned_spend(prev_tx, 0, from, vec![TXOutput::new(value, to).unwrap()]);
+    let mut prev_txs = HashMap::new();
+    prev_txs.insert(prev_tx.id.to_hex(), prev_tx.clone());
+    tx.sign(&from.private_key(), &prev_txs).unwrap()
+}
@@ -1,23 +1,30 @@
-use core::borrow::{Borrow, BorrowMut};
-use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::slice;
 
 use bincode::Error;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use rustc_serialize::hex::ToHex;
 use secp256k1::{Message, Secp256k1, SecretKey};
+use serde::Serialize;
 
 use crate::block::Sha256Hash;
+use crate::proof_of_work::convert_u64_to_u8_array;
 use crate::script_lang::{
-	pay_to_address_script, ScriptPubKey, ScriptSig, ScriptToken, StackValues,
+	htlc_script, lock_time_script, multisig_script, pay_to_address_script, pay_to_script_hash_script,
+	sequence_lock_script, ScriptPubKey, ScriptSig, SpendContext, StackValues,
 };
 use crate::wallet::{
-	KeyHash, private_key_to_public, PubKeyBytes,
+	Address, InvalidAddress, KeyHash, private_key_to_public, PubKeyBytes,
 };
 use crate::wallet::Wallet;
 
-const SUBSIDY: u64 = 5000;
+/// Lock times below this are block heights, the rest unix timestamps
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000;
+
+/// Bytes of the coinbase signature slot available for data, the rest is the extra-nonce
+const COINBASE_DATA_SIZE: usize = 56;
 
 big_array! {
     BigArray;
@@ -25,59 +32,219 @@ big_array! {
     64,
 }
 
+/// Immutable value type, operations like signing return a new transaction. Its
+/// encoding is part of the stored blocks, a change needs a new `STORE_VERSION`.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Transaction {
 	pub id: Sha256Hash,
-	pub vin: Vec<RefCell<TXInput>>,
+	pub vin: Vec<TXInput>,
 	pub vout: Vec<TXOutput>,
+	/// Height or timestamp, see `LOCK_TIME_THRESHOLD`, the transaction may not be
+	/// confirmed before. Zero means no lock.
+	pub lock_time: u64,
 }
 
 impl Transaction {
-	pub fn new_coinbase_tx(to: &String, data: String) -> Self {
+	/// Coinbase paying `value` to `to`. Blocks only accept it if `value` is at most
+	/// the subsidy at their height plus the fees of their other transactions.
+	pub fn new_coinbase_tx(to: &str, data: String, value: u64) -> Result<Self, InvalidAddress> {
 		let tx_in = TXInput {
 			tx_id: Sha256Hash::default(),
 			vout: -1,
 			script_sig: ScriptSig {
-				pub_key: [0; 33],
-				signature: [0; 64],
+				values: vec![StackValues::Signature(coinbase_data(data))],
+				redeem_script: None,
+				sighash: SigHashType::ALL,
 			},
+			sequence: 0,
 		};
-		let tx_out = TXOutput::new(SUBSIDY, to);
+		let tx_out = TXOutput::new(value, to)?;
 		let mut tx = Self {
 			id: Sha256Hash::default(),
-			vin: vec![RefCell::new(tx_in)],
+			vin: vec![tx_in],
 			vout: vec![tx_out],
+			lock_time: 0,
 		};
 		tx.id = tx.hash();
-		tx
+		Ok(tx)
 	}
-	pub fn new(vin: Vec<RefCell<TXInput>>, vout: Vec<TXOutput>) -> Self {
+	pub fn new(vin: Vec<TXInput>, vout: Vec<TXOutput>) -> Self {
 		let mut tx = Self {
 			id: Sha256Hash::default(),
-			vin: vin,
+			vin,
 			vout,
+			lock_time: 0,
 		};
 		tx.id = tx.hash();
 		tx
 	}
+	/// Copy that may only be confirmed after `lock_time`. The lock time is covered
+	/// by the id and the signatures, so it is set before signing.
+	pub fn with_lock_time(mut self, lock_time: u64) -> Self {
+		self.lock_time = lock_time;
+		self.id = self.hash();
+		self
+	}
+	/// Id over the outpoints and outputs, leaving out the signature scripts so
+	/// signing or re-encoding a signature keeps the id. The coinbase script is
+	/// data rather than a signature and stays in.
 	fn hash(&self) -> Sha256Hash {
-		let enc = match bincode::serialize(self) {
-			Ok(enc_dat) => enc_dat,
-			Err(e) => panic!("{}", e),
+		let coinbase_script = if self.is_coinbase() {
+			Some(&self.vin[0].script_sig)
+		} else {
+			None
+		};
+		let preimage = TxIdPreimage {
+			inputs: self.vin.iter().map(|vin| (vin.tx_id, vin.vout, vin.sequence)).collect(),
+			outputs: &self.vout,
+			lock_time: self.lock_time,
+			coinbase_script,
+		};
+		hash_serialized(&preimage)
+	}
+
+	/// Witness id, a hash over everything including the signature scripts.
+	pub fn wtxid(&self) -> Sha256Hash {
+		hash_serialized(&(&self.vin, &self.vout, self.lock_time))
+	}
+
+	/// Whether the stored id matches the content of the transaction.
+	pub fn has_valid_id(&self) -> bool {
+		self.id == self.hash()
+	}
+
+	/// Changes the coinbase id without touching its outputs, giving the miner a
+	/// fresh header once the block nonce space is exhausted.
+	pub fn increment_extra_nonce(&mut self) {
+		if !self.is_coinbase() {
+			return;
+		}
+		let slot = match self.vin[0].script_sig.values.get_mut(0) {
+			Some(StackValues::Signature(data)) => &mut data[COINBASE_DATA_SIZE..],
+			_ => return,
 		};
-		let mut hasher = Sha256::new();
-		hasher.input(&enc);
-		let mut hash = Sha256Hash::default();
-		hasher.result(&mut hash);
-		hash
+		let mut extra_nonce = [0; 8];
+		extra_nonce.copy_from_slice(slot);
+		let extra_nonce = u64::from_le_bytes(extra_nonce).wrapping_add(1);
+		slot.copy_from_slice(&convert_u64_to_u8_array(extra_nonce));
+		self.id = Sha256Hash::default();
+		self.id = self.hash();
+	}
+
+	/// Size of the serialized transaction in bytes, what fee rates are measured against.
+	pub fn size(&self) -> usize {
+		bincode::serialized_size(self).unwrap() as usize
+	}
+
+	/// Sum of the outputs, `None` if it overflows.
+	pub fn output_value(&self) -> Option<u64> {
+		self.vout.iter().try_fold(0u64, |sum, out| sum.checked_add(out.value))
+	}
+
+	/// Sum of the outputs spent by the inputs, looked up in `prev_txs`.
+	pub fn input_value(&self, prev_txs: &HashMap<String, Transaction>) -> Option<u64> {
+		self.vin.iter().try_fold(0u64, |sum, vin| {
+			let prev_tx = prev_txs.get(&vin.tx_id.to_hex())?;
+			let out = prev_tx.vout.get(vin.vout as usize)?;
+			sum.checked_add(out.value)
+		})
+	}
+
+	/// What the inputs carry beyond the outputs, `None` if the outputs exceed the inputs.
+	pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Option<u64> {
+		self.input_value(prev_txs)?.checked_sub(self.output_value()?)
+	}
+
+	/// Whether the lock time allows the transaction into a block at `height`
+	/// whose median time past is `time`.
+	pub fn is_final(&self, height: u64, time: u64) -> bool {
+		match self.lock_time {
+			0 => true,
+			lock_time if lock_time < LOCK_TIME_THRESHOLD => lock_time < height,
+			lock_time => lock_time < time,
+		}
 	}
 
 	pub fn is_coinbase(&self) -> bool {
 		self.vin.len() == 1
-			&& self.vin[0].borrow().tx_id == Sha256Hash::default()
-			&& self.vin[0].borrow().vout == -1
+			&& self.vin[0].tx_id == Sha256Hash::default()
+			&& self.vin[0].vout == -1
 	}
 
+	/// Message signed for input `index` when it spends `spent`. It commits to the
+	/// outpoints of the inputs, the index, the script and value of the spent output
+	/// and the outputs, narrowed down by the sighash type. `None` for an invalid
+	/// type, an index out of range, or SINGLE without an output at that index.
+	pub fn signature_hash(
+		&self,
+		index: usize,
+		spent: &TXOutput,
+		sighash: SigHashType,
+	) -> Option<Sha256Hash> {
+		if !sighash.is_valid() || index >= self.vin.len() {
+			return None;
+		}
+		let (inputs, first_input, input_index) = if sighash.is_anyone_can_pay() {
+			(&self.vin[index..=index], index, 0)
+		} else {
+			(&self.vin[..], 0, index as u32)
+		};
+		let outputs: &[TXOutput] = match sighash.base() {
+			SigHashType::NONE => &[],
+			SigHashType::SINGLE => slice::from_ref(self.vout.get(index)?),
+			_ => &self.vout,
+		};
+		// Without a commitment to every output, other inputs may change their sequence
+		let own_sequence_only = sighash.base() != SigHashType::ALL;
+		let preimage = SigHashPreimage {
+			inputs: inputs
+				.iter()
+				.zip(first_input..)
+				.map(|(vin, idx)| {
+					let sequence = if own_sequence_only && idx != index { 0 } else { vin.sequence };
+					(vin.tx_id, vin.vout, sequence)
+				})
+				.collect(),
+			input_index,
+			spent_script: &spent.script_pub_key,
+			spent_value: spent.value,
+			outputs,
+			lock_time: self.lock_time,
+			sighash,
+		};
+		Some(hash_serialized(&preimage))
+	}
+
+	/// Copy of the transaction with input `index`, spending `spent`, signed by
+	/// `private_key`.
+	pub fn sign_input(
+		&self,
+		index: usize,
+		private_key: &SecretKey,
+		spent: &TXOutput,
+		sighash: SigHashType,
+	) -> Option<Transaction> {
+		let hash = self.signature_hash(index, spent, sighash)?;
+		let secp = Secp256k1::signing_only();
+		let signature = secp.sign(&Message::from_slice(&hash).unwrap(), private_key);
+		let pub_key = private_key_to_public(private_key);
+		let script_sig = ScriptSig::pay_to_address(signature.serialize_compact(), pub_key, sighash);
+		Some(self.with_script_sig(index, script_sig))
+	}
+
+	/// Copy of the transaction with the unlocking data of input `index` replaced.
+	/// The id does not cover it and stays the same.
+	pub fn with_script_sig(&self, index: usize, script_sig: ScriptSig) -> Transaction {
+		let mut vin = self.vin.clone();
+		vin[index].script_sig = script_sig;
+		Transaction {
+			vin,
+			..self.clone()
+		}
+	}
+
+	/// Signs every input with `SigHashType::ALL`, looking the spent outputs up in
+	/// `prev_txs`.
 	pub fn sign(
 		&self,
 		private_key: &SecretKey,
@@ -86,82 +253,164 @@ impl Transaction {
 		if self.is_coinbase() {
 			return None;
 		}
-		let tx_copy = self.trimmed_copy();
-		for id in 0..tx_copy.vin.len() {
-			{
-				let mut vin = tx_copy.vin[id].borrow_mut();
-				let _prev_tx = &prev_txs[&vin.tx_id.to_hex()];
-				vin.script_sig = ScriptSig {
-					signature: [0; 64],
-					pub_key: private_key_to_public(private_key),
-				};
-			}
-			let mut vin = tx_copy.vin[id].borrow_mut();
-			let sign = Secp256k1::signing_only();
-			println!("Msg for sign {:?}", &tx_copy.id.to_vec());
-			println!("Key for sign {:?}", &vin.script_sig.pub_key.to_vec());
-			vin.script_sig.signature = sign
-				.sign(&Message::from_slice(&tx_copy.id).unwrap(), private_key)
-				.serialize_compact();
-			println!("Sign for sign {:?}", &vin.script_sig.signature.to_vec());
+		let mut tx = self.clone();
+		for index in 0..self.vin.len() {
+			let spent = spent_output(&self.vin[index], prev_txs)?;
+			tx = tx.sign_input(index, private_key, spent, SigHashType::ALL)?;
 		}
-		Some(tx_copy)
+		Some(tx)
 	}
 
 	pub fn verify(&self, prev_txs: &HashMap<String, Transaction>) -> bool {
-		let tx_copy = self.trimmed_copy();
-
-		for (_id, vin) in self.vin.iter().enumerate() {
-			let borrow_vin = vin.borrow();
-			let prev_tx = &prev_txs[&borrow_vin.tx_id.to_hex()];
-
-			let script_pub_key = &prev_tx.vout[borrow_vin.vout as usize].script_pub_key;
-
-			if let Ok(result) =
-			script_pub_key.verify(Some(&borrow_vin.script_sig), Some(&tx_copy.id))
-			{
-				if result {
-					continue;
-				} else {
-					return false;
-				}
-			} else {
-				return false;
+		if !self.has_valid_id() {
+			return false;
+		}
+		for (index, vin) in self.vin.iter().enumerate() {
+			let spent = match spent_output(vin, prev_txs) {
+				Some(spent) => spent,
+				None => return false,
+			};
+			let hash = match self.signature_hash(index, spent, vin.script_sig.sighash) {
+				Some(hash) => hash,
+				None => return false,
+			};
+			let context = SpendContext {
+				lock_time: self.lock_time,
+				sequence: vin.sequence,
+			};
+			let script_sig = Some(&vin.script_sig);
+			match spent.script_pub_key.verify_with_context(script_sig, Some(&hash), &context) {
+				Ok(true) => continue,
+				_ => return false,
 			}
 		}
 		true
 	}
 
-	fn trimmed_copy(&self) -> Self {
-		let mut inputs: Vec<RefCell<TXInput>> = Default::default();
-		let mut outputs: Vec<TXOutput> = Default::default();
-		for vin in self.vin.iter() {
-			let borrow_vin = vin.borrow();
-			inputs.push(RefCell::new(TXInput {
-				tx_id: borrow_vin.tx_id,
-				vout: borrow_vin.vout.clone(),
-				script_sig: ScriptSig {
-					pub_key: [0; 33],
-					signature: [0; 64],
-				},
-			}))
-		}
-		for vout in self.vout.iter() {
-			outputs.push(TXOutput {
-				value: vout.value,
-				script_pub_key: vout.script_pub_key.clone(),
-			})
+	pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+		bincode::deserialize(bytes)
+	}
+}
+
+/// Inputs and outputs with their scripts in ASM form.
+impl fmt::Display for Transaction {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Transaction {}", self.id.to_hex())?;
+		if self.lock_time > 0 {
+			write!(f, " \n   Lock time: {}", self.lock_time)?;
 		}
-		Self {
-			id: self.id,
-			vin: inputs,
-			vout: outputs,
+		for (index, vin) in self.vin.iter().enumerate() {
+			write!(
+				f,
+				" \n   Input {}: {}:{} sequence {} \n     {}",
+				index,
+				vin.tx_id.to_hex(),
+				vin.vout,
+				vin.sequence,
+				vin.script_sig
+			)?;
+			if let Some(ref redeem_script) = vin.script_sig.redeem_script {
+				write!(f, " \n     Redeem script: {}", redeem_script)?;
+			}
 		}
+		for (index, out) in self.vout.iter().enumerate() {
+			write!(f, " \n   Output {}: {} \n     {}", index, out.value, out.script_pub_key)?;
+		}
+		Ok(())
 	}
+}
 
-	pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
-		bincode::deserialize(bytes)
+fn spent_output<'a>(
+	vin: &TXInput,
+	prev_txs: &'a HashMap<String, Transaction>,
+) -> Option<&'a TXOutput> {
+	prev_txs.get(&vin.tx_id.to_hex())?.vout.get(vin.vout as usize)
+}
+
+fn hash_serialized<T: Serialize>(value: &T) -> Sha256Hash {
+	let enc = match bincode::serialize(value) {
+		Ok(enc_dat) => enc_dat,
+		Err(e) => panic!("{}", e),
+	};
+	let mut hasher = Sha256::new();
+	hasher.input(&enc);
+	let mut hash = Sha256Hash::default();
+	hasher.result(&mut hash);
+	hash
+}
+
+/// Data hashed into the transaction id.
+#[derive(Serialize)]
+struct TxIdPreimage<'a> {
+	inputs: Vec<(Sha256Hash, i64, u32)>,
+	outputs: &'a [TXOutput],
+	lock_time: u64,
+	coinbase_script: Option<&'a ScriptSig>,
+}
+
+/// Data hashed into the message an input signs.
+#[derive(Serialize)]
+struct SigHashPreimage<'a> {
+	inputs: Vec<(Sha256Hash, i64, u32)>,
+	input_index: u32,
+	spent_script: &'a ScriptPubKey,
+	spent_value: u64,
+	outputs: &'a [TXOutput],
+	lock_time: u64,
+	sighash: SigHashType,
+}
+
+/// Which parts of the transaction a signature commits to, stored next to the
+/// signature. The signed input and the output it spends are always covered.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SigHashType(u8);
+
+impl SigHashType {
+	/// Every output
+	pub const ALL: SigHashType = SigHashType(1);
+	/// No output, whoever completes the transaction decides where the value goes
+	pub const NONE: SigHashType = SigHashType(2);
+	/// Only the output at the index of the signed input
+	pub const SINGLE: SigHashType = SigHashType(3);
+	const ANYONECANPAY: u8 = 0x80;
+
+	/// Commits to the signed input only, so others may add inputs of their own.
+	pub fn anyone_can_pay(self) -> Self {
+		SigHashType(self.0 | Self::ANYONECANPAY)
+	}
+
+	pub fn is_anyone_can_pay(self) -> bool {
+		self.0 & Self::ANYONECANPAY != 0
+	}
+
+	fn base(self) -> Self {
+		SigHashType(self.0 & !Self::ANYONECANPAY)
+	}
+
+	fn is_valid(self) -> bool {
+		matches!(self.base(), SigHashType::ALL | SigHashType::NONE | SigHashType::SINGLE)
+	}
+}
+
+impl Default for SigHashType {
+	fn default() -> Self {
+		SigHashType::ALL
+	}
+}
+
+/// Coinbase inputs spend nothing, so the signature slot of their script carries
+/// arbitrary data instead, followed by an 8 byte extra-nonce. Empty data is
+/// replaced with random bytes to keep coinbase ids unique.
+fn coinbase_data(data: String) -> [u8; 64] {
+	let mut bytes = data.into_bytes();
+	if bytes.is_empty() {
+		bytes = rand::random::<[u8; 20]>().to_vec();
 	}
+	bytes.resize(COINBASE_DATA_SIZE, 0);
+	bytes.resize(64, 0);
+	let mut result = [0; 64];
+	result.copy_from_slice(&bytes);
+	result
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -169,21 +418,23 @@ pub struct TXInput {
 	pub tx_id: Sha256Hash,
 	pub vout: i64,
 	pub script_sig: ScriptSig,
+	/// Blocks the spent output must have been confirmed for before this input may
+	/// spend it, zero for no relative lock
+	pub sequence: u32,
 }
 
 impl TXInput {
 	pub fn uses_key(&self, pub_key: &PubKeyBytes) -> bool {
-		//        FIXME
-		self.script_sig.pub_key.to_vec() == pub_key.to_vec()
+		self.script_sig.pub_key() == Some(pub_key)
 	}
+	/// Unsigned input spending output `vout` of `income_transaction` with the key
+	/// of `from`. `Transaction::sign` fills in the signature.
 	pub fn new(income_transaction: &Transaction, vout: i64, from: &Wallet) -> Self {
 		Self {
-			script_sig: ScriptSig {
-				pub_key: from.public_key,
-				signature: from.sign(income_transaction.id.to_vec()),
-			},
+			script_sig: ScriptSig::unsigned(from.public_key),
 			vout,
 			tx_id: income_transaction.id,
+			sequence: 0,
 		}
 	}
 }
@@ -195,32 +446,128 @@ pub struct TXOutput {
 }
 
 impl TXOutput {
-	pub fn new(value: u64, address: &String) -> Self {
+	pub fn new(value: u64, address: &str) -> Result<Self, InvalidAddress> {
+		Ok(Self {
+			value,
+			script_pub_key: pay_to_address_script(&address.parse()?),
+		})
+	}
+	/// Output spendable with signatures of `required` of the `pub_keys`.
+	pub fn multisig(value: u64, required: u32, pub_keys: &[PubKeyBytes]) -> Self {
 		Self {
 			value,
-			script_pub_key: pay_to_address_script(address),
+			script_pub_key: multisig_script(required, pub_keys),
 		}
 	}
+	/// Output paying to `address` that cannot be spent by a transaction with a lock
+	/// time before `lock_time`, so not before that height or time either.
+	pub fn time_locked(value: u64, lock_time: u32, address: &str) -> Result<Self, InvalidAddress> {
+		Ok(Self {
+			value,
+			script_pub_key: lock_time_script(lock_time, &address.parse()?),
+		})
+	}
+	/// Output spendable by revealing `redeem_script` and satisfying it, the same as
+	/// paying to its `script_address`.
+	pub fn pay_to_script_hash(value: u64, redeem_script: &ScriptPubKey) -> Self {
+		Self {
+			value,
+			script_pub_key: pay_to_script_hash_script(redeem_script),
+		}
+	}
+	/// Output paying to `address` that an input can only spend once the output has
+	/// been confirmed for `blocks` blocks.
+	pub fn age_locked(value: u64, blocks: u32, address: &str) -> Result<Self, InvalidAddress> {
+		Ok(Self {
+			value,
+			script_pub_key: sequence_lock_script(blocks, &address.parse()?),
+		})
+	}
+	/// Output `recipient` claims with the preimage of `hash_lock`, or `sender`
+	/// takes back with a lock time of at least `timeout`. Both have to be key
+	/// addresses, script addresses are `InvalidAddress`.
+	pub fn htlc(
+		value: u64,
+		hash_lock: &Sha256Hash,
+		recipient: &str,
+		sender: &str,
+		timeout: u32,
+	) -> Result<Self, InvalidAddress> {
+		let key_hash = |address: &str| match address.parse()? {
+			Address::PubKeyHash(pub_key_hash) => Ok(pub_key_hash),
+			Address::ScriptHash(_) => Err(InvalidAddress(address.to_string())),
+		};
+		let (recipient, sender) = (key_hash(recipient)?, key_hash(sender)?);
+		Ok(Self {
+			value,
+			script_pub_key: htlc_script(hash_lock, &recipient, &sender, timeout),
+		})
+	}
+	/// Whether the output is the plain pay to key hash, or script hash, script of
+	/// `pub_key_hash`. Time locked, age locked and HTLC outputs name the hash too
+	/// but need more than the key to spend, so they are not counted.
 	pub fn is_locker_with_key(&self, pub_key_hash: &KeyHash) -> bool {
-		for token in &self.script_pub_key.script {
-			if let ScriptToken::Value(StackValues::PubKeyHash(find_hash)) = token {
-				if find_hash == pub_key_hash {
-					return true;
-				}
-			}
+		[Address::PubKeyHash(*pub_key_hash), Address::ScriptHash(*pub_key_hash)]
+			.iter()
+			.any(|address| self.script_pub_key == pay_to_address_script(address))
+	}
+}
+
+/// Fee a new transaction pays: a fixed amount, or a rate per byte of its size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fee {
+	Fixed(u64),
+	Rate(u64),
+}
+
+impl Fee {
+	/// Fee for a transaction of `size` bytes, `NotEnoughMoney` if it does not fit a u64.
+	pub fn for_size(&self, size: usize) -> Result<u64, TransactionError> {
+		match *self {
+			Fee::Fixed(fee) => Ok(fee),
+			Fee::Rate(rate) => rate
+				.checked_mul(size as u64)
+				.ok_or(TransactionError::NotEnoughMoney),
 		}
-		false
 	}
 }
 
+impl Default for Fee {
+	fn default() -> Self {
+		Fee::Fixed(0)
+	}
+}
+
+/// Reference to an unspent output together with the height of the block that created it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutPoint {
+	pub tx_id: Sha256Hash,
+	pub vout: i64,
+	pub value: u64,
+	pub height: u64,
+	/// False for coinbase outputs that cannot be spent yet
+	pub mature: bool,
+}
+
 #[derive(Debug)]
 pub enum TransactionError {
 	NotEnoughMoney,
+	UnknownInput,
+	NoRecipients,
+	/// Address that is not valid base58, has a bad checksum or an unknown version
+	InvalidAddress(String),
+}
+
+impl From<InvalidAddress> for TransactionError {
+	fn from(error: InvalidAddress) -> Self {
+		TransactionError::InvalidAddress(error.0)
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use crate::wallet::Wallet;
+	use crate::test_utils::unsigned_spend;
+	use crate::wallet::{script_address, Wallet};
 
 	use super::*;
 
@@ -228,15 +575,129 @@ mod tests {
 	fn sign_transaction() {
 		let from = Wallet::new();
 		let to = Wallet::new();
-		let coinbase = Transaction::new_coinbase_tx(&from.get_address(), "".to_string());
+		let coinbase =
+			Transaction::new_coinbase_tx(&from.get_address(), "".to_string(), 5000).unwrap();
 
-		let out = TXOutput::new(10, &to.get_address());
+		let out = TXOutput::new(10, &to.get_address()).unwrap();
 		let in_tx = TXInput::new(&coinbase, 0, &from);
-		let tx = Transaction::new(vec![RefCell::new(in_tx)], vec![out]);
+		let tx = Transaction::new(vec![in_tx], vec![out]);
 
 		let mut prev_txs = HashMap::new();
 		prev_txs.insert(coinbase.id.to_hex(), coinbase);
+		assert_eq!(tx.verify(&prev_txs), false);
 		let signed_tx = tx.sign(&from.private_key(), &prev_txs).unwrap();
 		assert_eq!(signed_tx.verify(&prev_txs), true)
 	}
+
+	#[test]
+	fn signature_hash_flags() {
+		let from = Wallet::new();
+		let to = Wallet::new();
+		let key = from.private_key();
+		let first =
+			Transaction::new_coinbase_tx(&from.get_address(), "".to_string(), 5000).unwrap();
+		let second =
+			Transaction::new_coinbase_tx(&from.get_address(), "".to_string(), 3000).unwrap();
+		let mut prev_txs = HashMap::new();
+		prev_txs.insert(first.id.to_hex(), first.clone());
+		prev_txs.insert(second.id.to_hex(), second.clone());
+		let input = |prev: &Transaction| TXInput::new(prev, 0, &from);
+		let tx = Transaction::new(
+			vec![input(&first), input(&second)],
+			vec![TXOutput::new(10, &to.get_address()).unwrap()],
+		);
+
+		let all = SigHashType::ALL;
+		let hash = tx.signature_hash(0, &first.vout[0], all).unwrap();
+		assert_ne!(Some(hash), tx.signature_hash(1, &first.vout[0], all));
+		assert_ne!(Some(hash), tx.signature_hash(0, &second.vout[0], all));
+		assert_eq!(tx.signature_hash(1, &second.vout[0], SigHashType::SINGLE), None);
+
+		let mut signed = tx.sign(&key, &prev_txs).unwrap();
+		assert!(signed.verify(&prev_txs));
+		signed.vout[0].value = 20;
+		signed.id = signed.hash();
+		assert!(!signed.verify(&prev_txs));
+
+		let mut unbound = tx
+			.sign_input(0, &key, &first.vout[0], SigHashType::NONE)
+			.and_then(|tx| tx.sign_input(1, &key, &second.vout[0], SigHashType::NONE))
+			.unwrap();
+		unbound.vout[0].value = 20;
+		unbound.id = unbound.hash();
+		assert!(unbound.verify(&prev_txs));
+
+		// Someone else may add an input to an ANYONECANPAY signature
+		let single = Transaction::new(vec![input(&first)], tx.vout.clone());
+		for (sighash, valid) in [(all.anyone_can_pay(), true), (all, false)] {
+			let mut extended = single.sign_input(0, &key, &first.vout[0], sighash).unwrap();
+			extended.vin.push(input(&second));
+			extended.id = extended.hash();
+			let extended = extended.sign_input(1, &key, &second.vout[0], all).unwrap();
+			assert_eq!(extended.verify(&prev_txs), valid);
+		}
+	}
+
+	#[test]
+	fn id_excludes_signatures() {
+		let from = Wallet::new();
+		let coinbase =
+			Transaction::new_coinbase_tx(&from.get_address(), "".to_string(), 5000).unwrap();
+		let output = TXOutput::new(10, &from.get_address()).unwrap();
+		let tx = unsigned_spend(&coinbase, 0, &from, vec![output]);
+		let mut prev_txs = HashMap::new();
+		prev_txs.insert(coinbase.id.to_hex(), coinbase);
+
+		let signed = tx.sign(&from.private_key(), &prev_txs).unwrap();
+		assert_eq!(signed.id, tx.id);
+		assert!(signed.has_valid_id());
+		assert_ne!(signed.wtxid(), tx.wtxid());
+		assert_ne!(signed.clone().with_lock_time(1).wtxid(), signed.wtxid());
+
+		let mut tampered = signed.clone();
+		tampered.vout[0].value = 20;
+		assert!(!tampered.has_valid_id());
+		assert!(!tampered.verify(&prev_txs));
+	}
+
+	#[test]
+	fn outputs_reject_invalid_addresses() {
+		let to = Wallet::new();
+		let address = to.get_address();
+		let mut typo = address.clone().into_bytes();
+		typo[3] = if typo[3] == b'2' { b'3' } else { b'2' };
+		let typo = String::from_utf8(typo).unwrap();
+		for invalid in &["garbage", "", typo.as_str()] {
+			let expected = Err(InvalidAddress(invalid.to_string()));
+			assert_eq!(TXOutput::new(10, invalid).map(|_| ()), expected);
+			assert_eq!(TXOutput::htlc(10, &[0; 32], invalid, &address, 5).map(|_| ()), expected);
+			assert_eq!(TXOutput::htlc(10, &[0; 32], &address, invalid, 5).map(|_| ()), expected);
+		}
+		let script_address = script_address(&multisig_script(1, &[to.public_key]));
+		assert!(TXOutput::new(10, &script_address).is_ok());
+		let htlc = TXOutput::htlc(10, &[0; 32], &script_address, &address, 5);
+		assert_eq!(htlc.map(|_| ()), Err(InvalidAddress(script_address)));
+	}
+
+	#[test]
+	fn increment_extra_nonce() {
+		let to = Wallet::new();
+		let mut coinbase =
+			Transaction::new_coinbase_tx(&to.get_address(), "data".to_string(), 5000).unwrap();
+		let id = coinbase.id;
+		coinbase.increment_extra_nonce();
+		assert_ne!(coinbase.id, id);
+		assert!(coinbase.is_coinbase());
+		assert_eq!(coinbase.vout[0].value, 5000);
+		match coinbase.vin[0].script_sig.values[0] {
+			StackValues::Signature(ref data) => assert_eq!(&data[..4], b"data"),
+			_ => panic!("coinbase data is missing"),
+		}
+	}
+
+	#[test]
+	fn is_send_sync() {
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<Transaction>();
+	}
 }
@@ -0,0 +1,204 @@
+use crate::blockchain::Blockchain;
+use crate::coin_selection::{CoinSelector, LargestFirst};
+use crate::script_lang::{ScriptPubKey, ScriptSig};
+use crate::transaction::{Fee, Transaction, TransactionError, TXInput, TXOutput};
+use crate::wallet::{Address, InvalidAddress, Wallet};
+
+/// Builds a signed payment from the spendable outputs of one wallet to any number
+/// of recipients. Whatever the selected outputs carry beyond the payments and the
+/// fee goes back to the change address, the sender's own address by default.
+/// Outputs are picked by the coin selector, largest first by default. An invalid
+/// address is reported by `build`.
+pub struct TransactionBuilder<'a> {
+    blockchain: &'a Blockchain,
+    from: &'a Wallet,
+    recipients: Vec<TXOutput>,
+    fee: Fee,
+    change_address: Option<String>,
+    selector: Box<dyn CoinSelector>,
+    lock_time: u64,
+    /// First invalid recipient or change address given
+    invalid_address: Option<String>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(blockchain: &'a Blockchain, from: &'a Wallet) -> Self {
+        TransactionBuilder {
+            blockchain,
+            from,
+            recipients: vec![],
+            fee: Fee::default(),
+            change_address: None,
+            selector: Box::new(LargestFirst),
+            lock_time: 0,
+            invalid_address: None,
+        }
+    }
+
+    pub fn pay(mut self, address: &str, amount: u64) -> Self {
+        match TXOutput::new(amount, address) {
+            Ok(output) => self.recipients.push(output),
+            Err(error) => self.invalid(error),
+        }
+        self
+    }
+
+    /// Pays `amount` to an output locked by a custom script.
+    pub fn pay_to_script(mut self, script_pub_key: ScriptPubKey, amount: u64) -> Self {
+        self.recipients.push(TXOutput {
+            value: amount,
+            script_pub_key,
+        });
+        self
+    }
+
+    pub fn fee(mut self, fee: Fee) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn change_address(mut self, address: &str) -> Self {
+        match address.parse::<Address>() {
+            Ok(_) => self.change_address = Some(address.to_string()),
+            Err(error) => self.invalid(error),
+        }
+        self
+    }
+
+    fn invalid(&mut self, error: InvalidAddress) {
+        if self.invalid_address.is_none() {
+            self.invalid_address = Some(error.0);
+        }
+    }
+
+    pub fn coin_selector(mut self, selector: Box<dyn CoinSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Height or timestamp the transaction may not be confirmed before.
+    pub fn lock_time(mut self, lock_time: u64) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// Selects enough outputs for the payments and the fee and signs the result. A
+    /// fee rate is charged on the size of the built transaction, so outputs are
+    /// selected again while the fee keeps growing.
+    pub fn build(&self) -> Result<Transaction, TransactionError> {
+        if let Some(ref address) = self.invalid_address {
+            return Err(TransactionError::InvalidAddress(address.clone()));
+        }
+        if self.recipients.is_empty() {
+            return Err(TransactionError::NoRecipients);
+        }
+        let amount = self
+            .recipients
+            .iter()
+            .try_fold(0u64, |sum, out| sum.checked_add(out.value))
+            .ok_or(TransactionError::NotEnoughMoney)?;
+        let change_address = match self.change_address {
+            Some(ref address) => address.clone(),
+            None => self.from.get_address(),
+        };
+
+        let mut required_fee = self.fee.for_size(0)?;
+        loop {
+            let total = amount
+                .checked_add(required_fee)
+                .ok_or(TransactionError::NotEnoughMoney)?;
+            let selection = self
+                .blockchain
+                .find_spendable_outs(self.from, total, self.selector.as_ref())
+                .ok_or(TransactionError::NotEnoughMoney)?;
+
+            let inputs: Vec<TXInput> = selection
+                .outputs
+                .iter()
+                .map(|out| TXInput {
+                    tx_id: out.tx_id,
+                    vout: out.vout,
+                    script_sig: ScriptSig::unsigned(self.from.public_key),
+                    sequence: 0,
+                })
+                .collect();
+            let mut outputs = self.recipients.clone();
+            if selection.change > 0 {
+                outputs.push(TXOutput::new(selection.change, &change_address)?);
+            }
+
+            let tx = Transaction::new(inputs, outputs).with_lock_time(self.lock_time);
+            let tx_fee = self.fee.for_size(tx.size())?;
+            if tx_fee <= required_fee {
+                return self
+                    .blockchain
+                    .sign_transaction(&tx, &self.from.private_key());
+            }
+            required_fee = tx_fee;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::script_lang::ScriptPubKey;
+    use crate::test_utils::test_chain;
+    use crate::transaction::{Fee, Transaction, TransactionError};
+
+    use super::TransactionBuilder;
+
+    #[test]
+    fn pays_several_recipients() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let alice = wallets.create_wallet().get_address();
+        let bob = wallets.create_wallet().get_address();
+        let change = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+
+        let empty = TransactionBuilder::new(&bc, from).build();
+        assert!(matches!(empty, Err(TransactionError::NoRecipients)));
+        let too_much = TransactionBuilder::new(&bc, from).pay(&alice, 3000).pay(&bob, 2001).build();
+        assert!(matches!(too_much, Err(TransactionError::NotEnoughMoney)));
+        let mut typo = alice.clone().into_bytes();
+        typo[3] = if typo[3] == b'2' { b'3' } else { b'2' };
+        let typo = String::from_utf8(typo).unwrap();
+        for address in &["garbage", "", typo.as_str()] {
+            let invalid = TransactionBuilder::new(&bc, from).pay(address, 10).build();
+            let expected = address.to_string();
+            assert!(matches!(invalid, Err(TransactionError::InvalidAddress(a)) if a == expected));
+        }
+        let bad_change = TransactionBuilder::new(&bc, from)
+            .pay(&alice, 10)
+            .change_address("garbage")
+            .build();
+        assert!(matches!(bad_change, Err(TransactionError::InvalidAddress(_))));
+        let huge_rate = TransactionBuilder::new(&bc, from)
+            .pay(&alice, 1000)
+            .fee(Fee::Rate(u64::MAX / 2))
+            .build();
+        assert!(matches!(huge_rate, Err(TransactionError::NotEnoughMoney)));
+
+        let tx = TransactionBuilder::new(&bc, from)
+            .pay(&alice, 1000)
+            .pay(&bob, 1500)
+            .fee(Fee::Fixed(50))
+            .change_address(&change)
+            .build()
+            .unwrap();
+        let values: Vec<u64> = tx.vout.iter().map(|out| out.value).collect();
+        assert_eq!(values, vec![1000, 1500, 2450]);
+        let script: ScriptPubKey = "2 OP_ADD 3 OP_EQUAL".parse().unwrap();
+        let custom = TransactionBuilder::new(&bc, from)
+            .pay_to_script(script.clone(), 100)
+            .build()
+            .unwrap();
+        assert_eq!(custom.vout[0].script_pub_key, script);
+
+        let coinbase = Transaction::new_coinbase_tx(&address, "".to_string(), 5050).unwrap();
+        bc.mine_block(vec![coinbase, tx]).unwrap();
+        assert_eq!(bc.get_balance(&alice).unwrap().confirmed, 1000);
+        assert_eq!(bc.get_balance(&bob).unwrap().confirmed, 1500);
+        assert_eq!(bc.get_balance(&change).unwrap().confirmed, 2450);
+        assert_eq!(bc.get_balance(&address).unwrap().confirmed, 5050);
+    }
+}
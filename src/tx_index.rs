@@ -0,0 +1,101 @@
+use bincode::Error;
+use rkv::{SingleStore, StoreOptions, Value, Writer};
+
+use crate::block::{Block, Sha256Hash};
+use crate::blockchain::Blockchain;
+
+/// Persistent map from transaction id to the block that confirmed it.
+pub(crate) struct TxIndex<'a> {
+    blockchain: &'a Blockchain,
+    store: SingleStore,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TxLocation {
+    pub(crate) block_hash: Sha256Hash,
+    /// Index of the transaction inside the block
+    pub(crate) position: u32,
+}
+
+impl TxLocation {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
+const TXINDEXBUCKET: &str = "txindex";
+
+impl<'a> TxIndex<'a> {
+    pub(crate) fn new(blockchain: &'a Blockchain) -> Self {
+        let store = blockchain
+            .store
+            .rkv()
+            .open_single(TXINDEXBUCKET, StoreOptions::create())
+            .unwrap();
+        TxIndex { blockchain, store }
+    }
+
+    /// Indexes every main chain block, for stores created before the index.
+    pub(crate) fn reindex(&self) {
+        let mut locations: Vec<(Sha256Hash, TxLocation)> = vec![];
+        for block in self.blockchain.iter() {
+            for (position, tx) in block.transactions.iter().enumerate() {
+                let location = TxLocation {
+                    block_hash: block.hash,
+                    position: position as u32,
+                };
+                locations.push((tx.id, location));
+            }
+        }
+        let env = self.blockchain.store.rkv();
+        let mut writer = env.write().unwrap();
+        self.store.clear(&mut writer).unwrap();
+        for (tx_id, location) in locations {
+            self.store
+                .put(&mut writer, tx_id, &Value::Blob(&location.serialize()))
+                .unwrap();
+        }
+        writer.commit().unwrap();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        let env = self.blockchain.store.rkv();
+        let reader = env.read().unwrap();
+        let mut iter = self.store.iter_start(&reader).unwrap();
+        iter.next().is_none()
+    }
+
+    /// Adds every transaction of the block inside the caller's write transaction.
+    pub(crate) fn update(&self, writer: &mut Writer, block: &Block) {
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let location = TxLocation {
+                block_hash: block.hash,
+                position: position as u32,
+            };
+            self.store
+                .put(writer, tx.id, &Value::Blob(&location.serialize()))
+                .unwrap();
+        }
+    }
+
+    /// Drops the transactions of a block being disconnected from the main chain.
+    pub(crate) fn remove(&self, writer: &mut Writer, block: &Block) {
+        for tx in &block.transactions {
+            self.store.delete(writer, tx.id).unwrap();
+        }
+    }
+
+    pub(crate) fn get(&self, tx_id: &Sha256Hash) -> Option<TxLocation> {
+        let env = self.blockchain.store.rkv();
+        let reader = env.read().unwrap();
+        match self.store.get(&reader, tx_id).unwrap() {
+            Some(Value::Blob(val)) => Some(TxLocation::from_bytes(val).unwrap()),
+            Some(_) => panic!("Wrong format"),
+            None => None,
+        }
+    }
+}
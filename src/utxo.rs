@@ -1,25 +1,31 @@
 
-use std::collections::HashMap;
-
 use bincode::Error;
-use rkv::{StoreOptions, Value};
-use rustc_serialize::hex::ToHex;
+use rkv::{SingleStore, StoreOptions, Value, Writer};
 
+use crate::block::{Block, Sha256Hash};
 use crate::blockchain::Blockchain;
-use crate::transaction::TXOutput;
+use crate::transaction::{OutPoint, TXOutput};
 use crate::wallet::KeyHash;
 
-struct UTXOSet<'a> {
-    blockchain: &'a mut Blockchain,
+pub(crate) struct UTXOSet<'a> {
+    blockchain: &'a Blockchain,
+    store: SingleStore,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct OutsSet {
-    outs: Vec<TXOutput>,
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct OutsSet {
+    pub(crate) height: u64,
+    /// Outputs of a coinbase only become spendable once they matured
+    pub(crate) coinbase: bool,
+    pub(crate) outs: Vec<(i64, TXOutput)>,
 }
 
 impl OutsSet {
-    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
         bincode::deserialize(bytes)
     }
 }
@@ -27,60 +33,201 @@ impl OutsSet {
 const UTXOBUCKET: &str = "utxo";
 
 impl<'a> UTXOSet<'a> {
-    fn reindex(self) {
+    pub(crate) fn new(blockchain: &'a Blockchain) -> Self {
+        let store = blockchain
+            .store
+            .rkv()
+            .open_single(UTXOBUCKET, StoreOptions::create())
+            .unwrap();
+        UTXOSet { blockchain, store }
+    }
+
+    /// Rebuilds the whole bucket from the blocks reachable from the current tip.
+    pub(crate) fn reindex(&self) {
+        let utxo = self.blockchain.find_utxo();
         let env = self.blockchain.store.rkv();
-        let store = env.open_single(UTXOBUCKET, StoreOptions::create()).unwrap();
         let mut writer = env.write().unwrap();
-        store.clear(&mut writer).unwrap();
-        //        utxo = self.find_utxo()
-        //        store.delete(writer, UTXOBUCKET)
+        self.store.clear(&mut writer).unwrap();
+        for (tx_id, outs) in utxo {
+            self.store
+                .put(&mut writer, tx_id, &Value::Blob(&outs.serialize()))
+                .unwrap();
+        }
+        writer.commit().unwrap();
     }
 
-    fn find_spendable_outputs(
-        &self,
-        pub_key_hash: KeyHash,
-        amount: u64,
-    ) -> (u64, HashMap<String, Vec<usize>>) {
-        let mut unspent_outputs: HashMap<String, Vec<usize>> = Default::default();
-
-        let mut accumulated: u64 = 0;
+    pub(crate) fn is_empty(&self) -> bool {
         let env = self.blockchain.store.rkv();
-        let store = env.open_single(UTXOBUCKET, StoreOptions::create()).unwrap();
         let reader = env.read().unwrap();
+        let mut iter = self.store.iter_start(&reader).unwrap();
+        iter.next().is_none()
+    }
 
-        let mut iter = store.iter_start(&reader).unwrap();
-        while let Some(Ok((tx_id, Some(Value::Blob(tx))))) = iter.next() {
-            let outs = OutsSet::from_bytes(&tx.to_vec()).unwrap().outs;
-            let tx_hex = tx_id.to_hex();
-            for (out_idx, out) in outs.iter().enumerate() {
-                if out.is_locker_with_key(&pub_key_hash) && accumulated < amount {
-                    accumulated += out.value;
-                    let unspent_out_in_tx = unspent_outputs
-                        .entry(tx_hex.clone())
-                        .or_insert(Default::default());
-
-                    (*unspent_out_in_tx).push(out_idx);
+    /// Applies a freshly mined block inside the caller's write transaction:
+    /// outputs spent by the block are removed and its new outputs are added.
+    pub(crate) fn update(&self, writer: &mut Writer, block: &Block) {
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let mut outs_set = match self.store.get(writer, vin.tx_id).unwrap() {
+                        Some(Value::Blob(val)) => OutsSet::from_bytes(val).unwrap(),
+                        _ => continue,
+                    };
+                    outs_set.outs.retain(|(out_idx, _)| *out_idx != vin.vout);
+                    if outs_set.outs.is_empty() {
+                        self.store.delete(writer, vin.tx_id).unwrap();
+                    } else {
+                        self.store
+                            .put(writer, vin.tx_id, &Value::Blob(&outs_set.serialize()))
+                            .unwrap();
+                    }
                 }
             }
+
+            let outs_set = OutsSet {
+                height: block.height,
+                coinbase: tx.is_coinbase(),
+                outs: tx
+                    .vout
+                    .iter()
+                    .enumerate()
+                    .map(|(out_idx, out)| (out_idx as i64, out.clone()))
+                    .collect(),
+            };
+            self.store
+                .put(writer, tx.id, &Value::Blob(&outs_set.serialize()))
+                .unwrap();
+        }
+    }
+
+    /// Undoes `update` for a block being disconnected: drops the outputs it created
+    /// and puts back the ones it spent, given as `(tx_id, vout, output)` together
+    /// with the record of the transaction that created them.
+    pub(crate) fn revert(
+        &self,
+        writer: &mut Writer,
+        block: &Block,
+        restored: Vec<(Sha256Hash, i64, TXOutput, OutsSet)>,
+    ) {
+        for tx in &block.transactions {
+            // Gone already if a later transaction of the block spent all its outputs
+            if self.store.get(writer, tx.id).unwrap().is_some() {
+                self.store.delete(writer, tx.id).unwrap();
+            }
+        }
+        for (tx_id, vout, out, record) in restored {
+            if block.transactions.iter().any(|tx| tx.id == tx_id) {
+                continue;
+            }
+            let mut outs_set = match self.store.get(writer, tx_id).unwrap() {
+                Some(Value::Blob(val)) => OutsSet::from_bytes(val).unwrap(),
+                Some(_) => panic!("Wrong format"),
+                None => record,
+            };
+            outs_set.outs.push((vout, out));
+            outs_set.outs.sort_by_key(|(out_idx, _)| *out_idx);
+            self.store
+                .put(writer, tx_id, &Value::Blob(&outs_set.serialize()))
+                .unwrap();
         }
-        (accumulated, unspent_outputs)
     }
-    fn find_utxo(&self, pub_key_hash: KeyHash) -> Vec<TXOutput> {
-        let mut utxo: Vec<TXOutput> = Default::default();
 
+    pub(crate) fn total_value(&self) -> u64 {
         let env = self.blockchain.store.rkv();
-        let store = env.open_single(UTXOBUCKET, StoreOptions::create()).unwrap();
         let reader = env.read().unwrap();
+        let mut total: u64 = 0;
+        let mut iter = self.store.iter_start(&reader).unwrap();
+        while let Some(Ok((_, Some(Value::Blob(val))))) = iter.next() {
+            let outs = OutsSet::from_bytes(val).unwrap().outs;
+            total += outs.iter().map(|(_, out)| out.value).sum::<u64>();
+        }
+        total
+    }
 
-        let mut iter = store.iter_start(&reader).unwrap();
-        while let Some(Ok((_tx_id, Some(Value::Blob(tx))))) = iter.next() {
-            let outs = OutsSet::from_bytes(&tx.to_vec()).unwrap().outs;
-            for out in outs {
-                if out.is_locker_with_key(&pub_key_hash) {
-                    utxo.push(out)
+    /// Whether the transaction `tx_id` still has unspent outputs.
+    pub(crate) fn contains(&self, tx_id: &Sha256Hash) -> bool {
+        let env = self.blockchain.store.rkv();
+        let reader = env.read().unwrap();
+        self.store.get(&reader, tx_id).unwrap().is_some()
+    }
+
+    pub(crate) fn get_output(&self, tx_id: &Sha256Hash, vout: i64) -> Option<TXOutput> {
+        let env = self.blockchain.store.rkv();
+        let reader = env.read().unwrap();
+        match self.store.get(&reader, tx_id).unwrap() {
+            Some(Value::Blob(val)) => OutsSet::from_bytes(val)
+                .unwrap()
+                .outs
+                .into_iter()
+                .find(|(out_idx, _)| *out_idx == vout)
+                .map(|(_, out)| out),
+            _ => None,
+        }
+    }
+
+    /// Outputs locked to the key that can be spent in the next block.
+    pub(crate) fn find_spendable_outputs(&self, pub_key_hash: &KeyHash) -> Vec<OutPoint> {
+        self.find_utxo(pub_key_hash)
+            .into_iter()
+            .filter(|out| out.mature)
+            .collect()
+    }
+
+    pub(crate) fn find_utxo(&self, pub_key_hash: &KeyHash) -> Vec<OutPoint> {
+        let mut utxo: Vec<OutPoint> = Default::default();
+
+        let env = self.blockchain.store.rkv();
+        let reader = env.read().unwrap();
+
+        let mut iter = self.store.iter_start(&reader).unwrap();
+        while let Some(Ok((tx_id, Some(Value::Blob(tx))))) = iter.next() {
+            let outs_set = OutsSet::from_bytes(tx).unwrap();
+            let mature = self.blockchain.is_spendable(&outs_set);
+            for (out_idx, out) in outs_set.outs {
+                if out.is_locker_with_key(pub_key_hash) {
+                    let mut id = Sha256Hash::default();
+                    id.copy_from_slice(tx_id);
+                    utxo.push(OutPoint {
+                        tx_id: id,
+                        vout: out_idx,
+                        value: out.value,
+                        height: outs_set.height,
+                        mature,
+                    })
                 }
             }
         }
         utxo
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::test_chain;
+    use crate::transaction::{Fee, Transaction};
+    use crate::wallet::hash_pub_key;
+
+    use super::UTXOSet;
+
+    #[test]
+    fn update_matches_reindex() {
+        let (_dir, mut wallets, mut bc, address) = test_chain();
+        let to = wallets.create_wallet().get_address();
+        let from = wallets.get(&address).unwrap();
+
+        let tx = bc.new_utxo_transaction(from, to, 1000, Fee::default()).unwrap();
+        let coinbase =
+            Transaction::new_coinbase_tx(&from.get_address(), "".to_string(), 5000).unwrap();
+        bc.mine_block(vec![coinbase, tx]).unwrap();
+
+        let from_hash = hash_pub_key(&from.public_key);
+        let utxo_set = UTXOSet::new(&bc);
+        let updated: Vec<u64> = utxo_set.find_utxo(&from_hash).iter().map(|o| o.value).collect();
+        utxo_set.reindex();
+        let reindexed: Vec<u64> = utxo_set.find_utxo(&from_hash).iter().map(|o| o.value).collect();
+        assert_eq!(updated, reindexed);
+        let spendable: Vec<u64> =
+            utxo_set.find_spendable_outputs(&from_hash).iter().map(|o| o.value).collect();
+        assert_eq!(spendable, updated);
+    }
+}
@@ -4,8 +4,8 @@ extern crate serde;
 extern crate serde_big_array;
 
 use std::collections::HashMap;
-use std::fmt;
 use std::str::FromStr;
+use std::{error, fmt};
 
 use bincode::Error;
 use bs58;
@@ -18,11 +18,15 @@ use secp256k1::key::{PublicKey, SecretKey};
 use secp256k1::Secp256k1;
 
 use crate::block::Sha256Hash;
+use crate::script_lang::{htlc_script, ScriptPubKey, ScriptSig};
 use crate::store::Store;
+use crate::transaction::{SigHashType, Transaction, TXOutput};
 
 use self::secp256k1::Message;
 
 static VERSION: u8 = 0;
+/// Version byte of pay-to-script-hash addresses
+static SCRIPT_VERSION: u8 = 5;
 
 pub type KeyHash = [u8; 20];
 
@@ -84,13 +88,7 @@ impl Wallet {
     }
 
     pub fn get_address(&self) -> String {
-        let mut payload: Vec<u8> = Default::default();
-        let pub_hash_key = hash_pub_key(&self.public_key);
-        payload.push(VERSION);
-        payload.extend_from_slice(&pub_hash_key);
-        let checksum = checksum(&payload);
-        payload.extend_from_slice(&checksum);
-        bs58::encode(payload).into_string()
+        Address::PubKeyHash(hash_pub_key(&self.public_key)).encode()
     }
 
     pub fn private_key(&self) -> SecretKey {
@@ -101,6 +99,118 @@ impl Wallet {
         sign.sign(&Message::from_slice(&data).unwrap(), &self.private_key())
             .serialize_compact()
     }
+    /// Signs input `index` of `tx` spending `spent`, for `combine_multisig` to
+    /// put together with the signatures of the other key holders.
+    pub fn sign_input(
+        &self,
+        tx: &Transaction,
+        index: usize,
+        spent: &TXOutput,
+        sighash: SigHashType,
+    ) -> Option<PartialSignature> {
+        let hash = tx.signature_hash(index, spent, sighash)?;
+        Some(PartialSignature {
+            pub_key: self.public_key,
+            signature: self.sign(hash.to_vec()),
+            sighash,
+        })
+    }
+    /// Output paying `value` to `recipient` once they reveal the preimage of
+    /// `hash_lock`, or back to this wallet with a lock time of at least `timeout`.
+    pub fn offer_htlc(
+        &self,
+        recipient: &Wallet,
+        value: u64,
+        hash_lock: &Sha256Hash,
+        timeout: u32,
+    ) -> TXOutput {
+        let recipient = hash_pub_key(&recipient.public_key);
+        let sender = hash_pub_key(&self.public_key);
+        TXOutput {
+            value,
+            script_pub_key: htlc_script(hash_lock, &recipient, &sender, timeout),
+        }
+    }
+    /// Unlocks HTLC input `index` of `tx` as the recipient, revealing `preimage`.
+    pub fn claim_htlc(
+        &self,
+        tx: &Transaction,
+        index: usize,
+        spent: &TXOutput,
+        preimage: &[u8],
+    ) -> Option<Transaction> {
+        let partial = self.sign_input(tx, index, spent, SigHashType::ALL)?;
+        let script_sig =
+            ScriptSig::htlc_claim(partial.signature, self.public_key, preimage, partial.sighash);
+        Some(tx.with_script_sig(index, script_sig))
+    }
+    /// Unlocks HTLC input `index` of `tx` as the sender, `tx` has to have a lock
+    /// time of at least the timeout.
+    pub fn refund_htlc(
+        &self,
+        tx: &Transaction,
+        index: usize,
+        spent: &TXOutput,
+    ) -> Option<Transaction> {
+        let partial = self.sign_input(tx, index, spent, SigHashType::ALL)?;
+        let script_sig =
+            ScriptSig::htlc_refund(partial.signature, self.public_key, partial.sighash);
+        Some(tx.with_script_sig(index, script_sig))
+    }
+}
+
+/// Signature of one key holder over a multisig input.
+#[derive(Clone)]
+pub struct PartialSignature {
+    pub pub_key: PubKeyBytes,
+    pub signature: SignatureBytes,
+    pub sighash: SigHashType,
+}
+
+/// Unlocks multisig input `index` of `tx` with the signatures gathered from the
+/// key holders, in the order the spent script lists their keys. `None` if fewer
+/// than the required number of keys signed, or they used different sighash types.
+pub fn combine_multisig(
+    tx: &Transaction,
+    index: usize,
+    spent: &TXOutput,
+    partials: &[PartialSignature],
+) -> Option<Transaction> {
+    let script_sig = multisig_script_sig(&spent.script_pub_key, partials)?;
+    Some(tx.with_script_sig(index, script_sig))
+}
+
+/// `combine_multisig` for a pay-to-script-hash output with a multisig redeem script.
+pub fn combine_multisig_script_hash(
+    tx: &Transaction,
+    index: usize,
+    redeem_script: &ScriptPubKey,
+    partials: &[PartialSignature],
+) -> Option<Transaction> {
+    let mut script_sig = multisig_script_sig(redeem_script, partials)?;
+    script_sig.redeem_script = Some(redeem_script.clone());
+    Some(tx.with_script_sig(index, script_sig))
+}
+
+fn multisig_script_sig(
+    script: &ScriptPubKey,
+    partials: &[PartialSignature],
+) -> Option<ScriptSig> {
+    let (required, pub_keys) = script.multisig_keys()?;
+    let signed: Vec<&PartialSignature> = pub_keys
+        .iter()
+        .filter_map(|pub_key| partials.iter().find(|partial| partial.pub_key == *pub_key))
+        .take(required)
+        .collect();
+    if signed.len() < required {
+        return None;
+    }
+    let sighash = signed.first().map_or(SigHashType::ALL, |partial| partial.sighash);
+    if signed.iter().any(|partial| partial.sighash != sighash) {
+        return None;
+    }
+    let signatures = signed.iter().map(|partial| partial.signature).collect();
+    Some(ScriptSig::multisig(signatures, sighash))
 }
 
 pub fn private_key_to_public(key: &SecretKey) -> PubKeyBytes {
@@ -109,8 +219,22 @@ pub fn private_key_to_public(key: &SecretKey) -> PubKeyBytes {
 }
 
 pub fn hash_pub_key(key: &PubKeyBytes) -> KeyHash {
+    hash160(key)
+}
+
+/// Hash a pay-to-script-hash output commits to.
+pub fn hash_script(script: &ScriptPubKey) -> KeyHash {
+    hash160(&bincode::serialize(script).unwrap())
+}
+
+/// Address of the pay-to-script-hash output locked by `script`.
+pub fn script_address(script: &ScriptPubKey) -> String {
+    Address::ScriptHash(hash_script(script)).encode()
+}
+
+pub(crate) fn hash160(data: &[u8]) -> KeyHash {
     let mut hasher = Sha256::new();
-    hasher.input(key);
+    hasher.input(data);
     let mut hash = Sha256Hash::default();
     hasher.result(&mut hash);
     // hash to ripemd160
@@ -138,18 +262,85 @@ fn checksum(payload: &Vec<u8>) -> [u8; 4] {
     checksum_hash
 }
 
-pub fn address_to_pub_hash(address: &String) -> KeyHash {
-    let pub_key_hash = bs58::decode(address.clone().into_bytes())
-        .into_vec()
-        .unwrap();
-    let mut pub_key_bytes: KeyHash = [0; 20];
-    pub_key_bytes.copy_from_slice(&pub_key_hash[1..pub_key_hash.len() - 4]);
-    pub_key_bytes
+/// Decoded address, telling apart what kind of output it pays to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    /// Output locked to the key with this hash
+    PubKeyHash(KeyHash),
+    /// Output locked to the redeem script with this hash
+    ScriptHash(KeyHash),
+}
+
+impl Address {
+    pub fn encode(&self) -> String {
+        let (version, hash) = match *self {
+            Address::PubKeyHash(ref hash) => (VERSION, hash),
+            Address::ScriptHash(ref hash) => (SCRIPT_VERSION, hash),
+        };
+        let mut payload: Vec<u8> = vec![version];
+        payload.extend_from_slice(hash);
+        let checksum = checksum(&payload);
+        payload.extend_from_slice(&checksum);
+        bs58::encode(payload).into_string()
+    }
+
+    /// `None` unless the address has a known version and a valid checksum.
+    pub fn decode(address: &str) -> Option<Self> {
+        let payload = bs58::decode(address).into_vec().ok()?;
+        if payload.len() != 25 || checksum(&payload[..21].to_vec())[..] != payload[21..] {
+            return None;
+        }
+        let mut hash: KeyHash = [0; 20];
+        hash.copy_from_slice(&payload[1..21]);
+        match payload[0] {
+            version if version == VERSION => Some(Address::PubKeyHash(hash)),
+            version if version == SCRIPT_VERSION => Some(Address::ScriptHash(hash)),
+            _ => None,
+        }
+    }
+
+    pub fn hash(&self) -> &KeyHash {
+        match self {
+            Address::PubKeyHash(hash) | Address::ScriptHash(hash) => hash,
+        }
+    }
+}
+
+/// `Address::decode` reporting the rejected text.
+impl FromStr for Address {
+    type Err = InvalidAddress;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        Address::decode(address).ok_or_else(|| InvalidAddress(address.to_string()))
+    }
+}
+
+/// Text that is not valid base58, has a bad checksum or an unknown version.
+#[derive(Debug, PartialEq)]
+pub struct InvalidAddress(pub String);
+
+impl fmt::Display for InvalidAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid address {}", self.0)
+    }
+}
+
+impl error::Error for InvalidAddress {
+    fn description(&self) -> &str {
+        "Invalid address"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Wallets {
     pub wallets: HashMap<String, Wallet>,
+    /// Directory the wallets were loaded from, new wallets are saved there
+    #[serde(skip)]
+    path: String,
 }
 
 impl Wallets {
@@ -161,7 +352,7 @@ impl Wallets {
         let env = store.rkv();
         let single_store = store.single_store();
         let reader = env.read().unwrap();
-        let wallets = match single_store.get(&reader, "wallets") {
+        let mut wallets = match single_store.get(&reader, "wallets") {
             Ok(l_opt) => match l_opt {
                 Some(l) => {
                     if let Value::Blob(val) = l {
@@ -172,12 +363,14 @@ impl Wallets {
                 }
                 None => Self {
                     wallets: Default::default(),
+                    path: String::new(),
                 },
             },
             Err(e) => {
                 panic!("{}", e);
             }
         };
+        wallets.path = path_str;
         wallets
     }
     pub fn save_to_file(&self, path_str: String) {
@@ -201,16 +394,30 @@ impl Wallets {
         let wallet = Wallet::new();
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
-        self.save_to_file("wallets".to_string());
+        self.save_to_file(self.path.clone());
         self.wallets.get(&address).unwrap()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use rustc_serialize::hex::ToHex;
+
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+
+    use crate::block::Sha256Hash;
+    use crate::script_lang::{multisig_script, ScriptPubKey, ScriptSig, ScriptToken, StackValues};
+    use crate::transaction::{SigHashType, Transaction, TXInput, TXOutput};
+    use crate::test_utils::{funding_tx, test_wallets, unsigned_spend};
     use crate::wallet::Wallets;
 
-    use super::Wallet;
+    use super::{
+        combine_multisig, combine_multisig_script_hash, hash_pub_key, hash_script, script_address,
+        Address, Wallet,
+    };
 
     #[test]
     fn get_address() {
@@ -240,13 +447,150 @@ mod tests {
 
     #[test]
     fn wallets_create() {
-        let mut wallets = Wallets::new("wallets".to_string());
+        let (dir, mut wallets) = test_wallets();
         let _wallet = wallets.create_wallet();
-        wallets.save_to_file("wallets".to_string());
-        let from_wallets = Wallets::new("wallets".to_string());
+        let path = dir.path().join("wallets").to_str().unwrap().to_string();
+        let from_wallets = Wallets::new(path);
         let _a = wallets.wallets.len();
         assert_eq!(wallets.wallets.len(), from_wallets.wallets.len());
         assert_ne!(wallets.wallets.len(), 0);
         println!("{}", wallets.wallets.len())
     }
+
+    #[test]
+    fn multisig_two_of_three() {
+        let owners = [Wallet::new(), Wallet::new(), Wallet::new()];
+        let outsider = Wallet::new();
+        let pub_keys: Vec<_> = owners.iter().map(|wallet| wallet.public_key).collect();
+        let funding = funding_tx(&owners[0], vec![TXOutput::multisig(5000, 2, &pub_keys)]);
+        let spent = &funding.vout[0];
+        let input = TXInput {
+            script_sig: ScriptSig::multisig(vec![], SigHashType::ALL),
+            ..TXInput::new(&funding, 0, &owners[0])
+        };
+        let output = TXOutput::new(5000, &outsider.get_address()).unwrap();
+        let tx = Transaction::new(vec![input], vec![output]);
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(funding.id.to_hex(), funding.clone());
+
+        let sign = |wallet: &Wallet| wallet.sign_input(&tx, 0, spent, SigHashType::ALL).unwrap();
+        let partials = vec![sign(&owners[2]), sign(&outsider), sign(&owners[0])];
+        let signed = combine_multisig(&tx, 0, spent, &partials).unwrap();
+        assert!(signed.verify(&prev_txs));
+        let signed = combine_multisig(&tx, 0, spent, &[sign(&owners[1]), sign(&owners[2])]);
+        assert!(signed.unwrap().verify(&prev_txs));
+
+        assert!(combine_multisig(&tx, 0, spent, &[sign(&owners[1]), sign(&outsider)]).is_none());
+        // Signatures have to follow the order of the keys
+        let swapped = vec![sign(&owners[2]).signature, sign(&owners[0]).signature];
+        let swapped = tx.with_script_sig(0, ScriptSig::multisig(swapped, SigHashType::ALL));
+        assert!(!swapped.verify(&prev_txs));
+        let outsider_sig = vec![sign(&owners[0]).signature, sign(&outsider).signature];
+        let forged = tx.with_script_sig(0, ScriptSig::multisig(outsider_sig, SigHashType::ALL));
+        assert!(!forged.verify(&prev_txs));
+    }
+
+    #[test]
+    fn address_types() {
+        let wallet = Wallet::new();
+        let script = multisig_script(1, &[wallet.public_key]);
+        let key_address = wallet.get_address();
+        let script_address = script_address(&script);
+        assert_ne!(key_address, script_address);
+        assert_eq!(
+            Address::decode(&key_address),
+            Some(Address::PubKeyHash(hash_pub_key(&wallet.public_key)))
+        );
+        let script_hash = hash_script(&script);
+        assert_eq!(Address::decode(&script_address), Some(Address::ScriptHash(script_hash)));
+        let output = TXOutput::new(10, &script_address).unwrap();
+        assert_eq!(output.script_pub_key.script_hash(), Some(script_hash));
+
+        let mut corrupted = script_address.into_bytes();
+        corrupted[5] = if corrupted[5] == b'2' { b'3' } else { b'2' };
+        assert_eq!(Address::decode(&String::from_utf8(corrupted).unwrap()), None);
+    }
+
+    #[test]
+    fn pay_to_script_hash() {
+        let owners = [Wallet::new(), Wallet::new(), Wallet::new()];
+        let pub_keys: Vec<_> = owners.iter().map(|wallet| wallet.public_key).collect();
+        let redeem_script = multisig_script(2, &pub_keys);
+        let sum = ScriptPubKey {
+            script: vec![
+                ScriptToken::Value(StackValues::Value(1)),
+                ScriptToken::Value(StackValues::Value(2)),
+                ScriptToken::OpAdd,
+                ScriptToken::Value(StackValues::Value(3)),
+                ScriptToken::OpEqual,
+            ],
+        };
+        let outputs = vec![
+            TXOutput::new(5000, &script_address(&redeem_script)).unwrap(),
+            TXOutput::pay_to_script_hash(1000, &sum),
+        ];
+        let funding = funding_tx(&owners[0], outputs);
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(funding.id.to_hex(), funding.clone());
+        let spend = |vout: i64, script_sig: ScriptSig| {
+            let input = TXInput {
+                script_sig,
+                ..TXInput::new(&funding, vout, &owners[0])
+            };
+            let output = TXOutput::new(900, &owners[1].get_address()).unwrap();
+            Transaction::new(vec![input], vec![output])
+        };
+
+        let tx = spend(0, ScriptSig::multisig(vec![], SigHashType::ALL));
+        let spent = &funding.vout[0];
+        let sign = |wallet: &Wallet| wallet.sign_input(&tx, 0, spent, SigHashType::ALL).unwrap();
+        let partials = vec![sign(&owners[0]), sign(&owners[1])];
+        let signed = combine_multisig_script_hash(&tx, 0, &redeem_script, &partials).unwrap();
+        assert!(signed.verify(&prev_txs));
+        // The revealed script has to hash to the committed one
+        let other_script = multisig_script(2, &pub_keys[..2]);
+        let mut wrong_script = signed.clone();
+        wrong_script.vin[0].script_sig.redeem_script = Some(other_script);
+        assert!(!wrong_script.verify(&prev_txs));
+        assert!(combine_multisig(&tx, 0, spent, &partials).is_none());
+
+        let revealed = spend(1, ScriptSig::pay_to_script_hash(vec![], sum, SigHashType::ALL));
+        assert!(revealed.verify(&prev_txs));
+        assert!(!spend(1, ScriptSig::multisig(vec![], SigHashType::ALL)).verify(&prev_txs));
+    }
+
+    #[test]
+    fn hash_time_locked_contract() {
+        let sender = Wallet::new();
+        let recipient = Wallet::new();
+        let preimage = b"swap secret";
+        let mut hash_lock = Sha256Hash::default();
+        let mut hasher = Sha256::new();
+        hasher.input(preimage);
+        hasher.result(&mut hash_lock);
+
+        let offer = sender.offer_htlc(&recipient, 5000, &hash_lock, 100);
+        let funding = funding_tx(&sender, vec![offer]);
+        let spent = &funding.vout[0];
+        assert!(!spent.is_locker_with_key(&hash_pub_key(&sender.public_key)));
+        assert!(!spent.is_locker_with_key(&hash_pub_key(&recipient.public_key)));
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(funding.id.to_hex(), funding.clone());
+        let spend = |to: &Wallet, lock_time: u64| {
+            let output = TXOutput::new(5000, &to.get_address()).unwrap();
+            unsigned_spend(&funding, 0, to, vec![output]).with_lock_time(lock_time)
+        };
+
+        let claim = spend(&recipient, 0);
+        assert!(recipient.claim_htlc(&claim, 0, spent, preimage).unwrap().verify(&prev_txs));
+        let wrong_preimage = recipient.claim_htlc(&claim, 0, spent, b"guess").unwrap();
+        assert!(!wrong_preimage.verify(&prev_txs));
+        let stolen = spend(&sender, 0);
+        assert!(!sender.claim_htlc(&stolen, 0, spent, preimage).unwrap().verify(&prev_txs));
+
+        assert!(!sender.refund_htlc(&spend(&sender, 99), 0, spent).unwrap().verify(&prev_txs));
+        let refund = spend(&sender, 100);
+        assert!(sender.refund_htlc(&refund, 0, spent).unwrap().verify(&prev_txs));
+        assert!(!recipient.refund_htlc(&refund, 0, spent).unwrap().verify(&prev_txs));
+    }
 }